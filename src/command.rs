@@ -1,28 +1,266 @@
+use std::fmt;
+
+/// An error found while splitting a command line into terms
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError {
+    UnterminatedQuote(char, usize) // The quote character and where it was opened (0-indexed column)
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CommandError::UnterminatedQuote(quote, column) => write!(f, "Unterminated {} quote (opened at column {}).", quote, column)
+        }
+    }
+}
+
+/// A command of the command prompt, whatever name or alias it was typed with (see COMMANDS)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CommandName {
+    Autofit,
+    Config,
+    Delete,
+    Delimiter,
+    Earlier,
+    Edit, // Back to editing, or open a file (like Open) if given one
+    Fill,
+    Filter,
+    Freeze,
+    Insert,
+    Later,
+    Nav,
+    New,
+    ForceNew,
+    NoHighlight,
+    Open,
+    ForceOpen,
+    Path,
+    Quit,
+    ForceQuit,
+    Redo,
+    Replace,
+    Save,
+    Sort,
+    Undo,
+    UndoList,
+    Unfreeze,
+    Width
+}
+
+/// The names and aliases of the commands, sorted (the command prompt runs and completes only these)
+pub const COMMANDS: &[(&str, CommandName)] = &[
+    ("autofit", CommandName::Autofit),
+    ("config", CommandName::Config),
+    ("d", CommandName::Delete),
+    ("delete", CommandName::Delete),
+    ("delim", CommandName::Delimiter),
+    ("delimiter", CommandName::Delimiter),
+    ("e", CommandName::Edit),
+    ("e!", CommandName::ForceOpen),
+    ("earlier", CommandName::Earlier),
+    ("edit", CommandName::Edit),
+    ("fill", CommandName::Fill),
+    ("filter", CommandName::Filter),
+    ("freeze", CommandName::Freeze),
+    ("g", CommandName::Nav),
+    ("i", CommandName::Insert),
+    ("insert", CommandName::Insert),
+    ("later", CommandName::Later),
+    ("nav", CommandName::Nav),
+    ("new", CommandName::New),
+    ("new!", CommandName::ForceNew),
+    ("noh", CommandName::NoHighlight),
+    ("nohlsearch", CommandName::NoHighlight),
+    ("o", CommandName::Insert),
+    ("open", CommandName::Open),
+    ("open!", CommandName::ForceOpen),
+    ("path", CommandName::Path),
+    ("q", CommandName::Quit),
+    ("q!", CommandName::ForceQuit),
+    ("quit", CommandName::Quit),
+    ("quit!", CommandName::ForceQuit),
+    ("r", CommandName::Redo),
+    ("redo", CommandName::Redo),
+    ("replace", CommandName::Replace),
+    ("save", CommandName::Save),
+    ("sort", CommandName::Sort),
+    ("u", CommandName::Undo),
+    ("ul", CommandName::UndoList),
+    ("undo", CommandName::Undo),
+    ("undolist", CommandName::UndoList),
+    ("unfreeze", CommandName::Unfreeze),
+    ("w", CommandName::Save),
+    ("width", CommandName::Width)
+];
+
+/// Get the command a name or alias stands for, if any
+pub fn command_name(name: &str) -> Option<CommandName> {
+    COMMANDS.iter().find(|(n, _)| *n == name).map(|&(_, command)| command)
+}
+
+/// A term of a command line, as typed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub text: String, // The term without its quotes and escapes
+    pub start: usize, // Byte index of the start of the term in the line
+    pub end: usize, // Byte index of the end of the term in the line
+    pub quote: Option<char> // The quote the term starts with, if any
+}
+
+/// Split a command line into terms, and get where a quote was left open if any
+/// Terms are separated by any amount of whitespace; single quotes keep everything in them, double quotes keep everything
+/// but `\"` and `\\`, and outside of quotes a backslash escapes a following whitespace, quote or backslash
+/// (other backslashes are kept, so Windows paths can be typed as is)
+pub fn tokenize(line: &str) -> (Vec<Token>, Option<(char, usize)>) {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut current: Option<Token> = None;
+    let mut quote: Option<(char, usize)> = None;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let escaped = chars.peek().map(|&(_, next)| next).filter(|&next| match quote {
+            Some(('"', _)) => next == '"' || next == '\\',
+            Some(_) => false,
+            None => next.is_whitespace() || next == '"' || next == '\'' || next == '\\'
+        });
+        let token = current.get_or_insert(Token { text: String::new(), start: i, end: i, quote: None });
+        match (c, quote, escaped) {
+            ('\\', _, Some(next)) => {
+                chars.next();
+                token.text.push(next);
+            }
+            (_, Some((q, _)), _) if c == q => {
+                quote = None;
+            }
+            (_, Some(_), _) => {
+                token.text.push(c);
+            }
+            ('"' | '\'', None, _) => {
+                quote = Some((c, line[..i].chars().count()));
+                if token.start == i {
+                    token.quote = Some(c);
+                }
+            }
+            (_, None, _) if c.is_whitespace() => {
+                // End of the term (or more whitespace between terms)
+                if let Some(token) = current.take().filter(|t| t.start != i) {
+                    tokens.push(token);
+                }
+                continue;
+            }
+            (_, None, _) => {
+                token.text.push(c);
+            }
+        }
+        token.end = chars.peek().map_or(line.len(), |&(next, _)| next);
+    }
+    tokens.extend(current);
+    (tokens, quote)
+}
+
+/// A command typed into the command prompt, split into terms
 pub struct Command {
     terms: Vec<String>
 }
 
 impl Command {
-    pub fn from(input_line: &str) -> Command {
-        let mut terms: Vec<String> =
-            input_line.trim().split(' ')
-            .map(|v| v.trim().to_string())
-            .collect();
+    /// Parse a command line into terms (see tokenize), or return an error if a quote is left open
+    pub fn parse(input_line: &str) -> Result<Command, CommandError> {
+        let (tokens, quote) = tokenize(input_line);
+        if let Some((quote, column)) = quote {
+            return Err(CommandError::UnterminatedQuote(quote, column));
+        }
+        let mut terms: Vec<String> = tokens.into_iter().map(|t| t.text).collect();
         // Remove leading ':' (for vim users)
-        if terms.len() > 0 {
+        if !terms.is_empty() {
             if let Some(noprefix) = terms[0].strip_prefix(':') {
                 terms[0] = noprefix.to_string();
             }
         }
-        Command { terms }
+        Ok(Command { terms })
     }
 
     pub fn len(&self) -> usize {
         self.terms.len()
     }
 
-    /// Get a term of the command, 0-indexed (will panic if out of bounds)
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// Get the command named by the first term, if it is one
+    pub fn name(&self) -> Option<CommandName> {
+        command_name(self.term(0))
+    }
+
+    /// Get the terms of the command starting at a 0-indexed term
+    pub fn terms_from(&self, i: usize) -> Vec<&str> {
+        self.terms.iter().skip(i).map(|t| t.as_str()).collect()
+    }
+
+    /// Get a term of the command, 0-indexed, or None if out of bounds
+    pub fn get(&self, i: usize) -> Option<&str> {
+        self.terms.get(i).map(|t| t.as_str())
+    }
+
+    /// Get a term of the command, 0-indexed (empty if out of bounds)
     pub fn term(&self, i: usize) -> &str {
-        &self.terms[i]
+        self.get(i).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(line: &str) -> Vec<String> {
+        tokenize(line).0.into_iter().map(|t| t.text).collect()
+    }
+
+    #[test]
+    fn splits_on_whitespace() {
+        assert_eq!(terms("  open   data.csv\t"), ["open", "data.csv"]);
+        assert!(terms("   ").is_empty());
+        let (tokens, quote) = tokenize("w a.csv");
+        assert_eq!(quote, None);
+        assert_eq!((tokens[1].start, tokens[1].end), (2, 7));
+    }
+
+    #[test]
+    fn reads_quotes_and_escapes() {
+        assert_eq!(terms(r#"replace "old name" 'new "name"'"#), ["replace", "old name", "new \"name\""]);
+        assert_eq!(terms(r#"fill "a \"b\" \\ c""#), ["fill", r#"a "b" \ c"#]);
+        assert_eq!(terms(r"open My\ Data.csv C:\data\x.csv"), ["open", "My Data.csv", r"C:\data\x.csv"]);
+        assert_eq!(terms(r#"fill a"b c"d"#), ["fill", "ab cd"]);
+        assert_eq!(tokenize(r#"open "x"#).0[1].quote, Some('"'));
+    }
+
+    #[test]
+    fn reports_unterminated_quotes() {
+        assert_eq!(tokenize("fill 'a b").1, Some(('\'', 5)));
+        let Err(e) = Command::parse(r#"replace "é a"#) else {
+            panic!("parsed an unterminated quote");
+        };
+        assert_eq!(e, CommandError::UnterminatedQuote('"', 8));
+    }
+
+    #[test]
+    fn gets_terms_safely() {
+        let command = Command::parse(":nav 3 4").unwrap();
+        assert_eq!(command.len(), 3);
+        assert_eq!(command.term(0), "nav");
+        assert_eq!(command.get(3), None);
+        assert_eq!(command.term(9), "");
+        assert_eq!(command.terms_from(1), ["3", "4"]);
+        assert!(command.terms_from(5).is_empty());
+        assert!(Command::parse("").unwrap().is_empty());
+        assert_eq!(Command::parse("").unwrap().term(0), "");
+    }
+
+    #[test]
+    fn names_commands() {
+        assert!(COMMANDS.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert_eq!(Command::parse(":q!").unwrap().name(), Some(CommandName::ForceQuit));
+        assert_eq!(command_name("o"), Some(CommandName::Insert));
+        assert_eq!(command_name("nope"), None);
     }
 }
@@ -0,0 +1,188 @@
+use std::{ fs, path::Path };
+use crate::configdata::ConfigData;
+use crate::command::{ self, CommandName };
+
+/// Possible completions of the word before the cursor
+pub struct Completions {
+    pub start: usize, // Byte index of the start of the word
+    pub candidates: Vec<String> // Words that can replace it (quoted if needed), sorted
+}
+
+/// What a term of a command can be completed with
+enum TermKind {
+    Commands,
+    Words(&'static [&'static str]),
+    ConfigKey,
+    Path,
+    Nothing
+}
+
+/// Get what a term of a command can be completed with, from the command and the terms before it
+fn term_kind(command: &str, index: usize, before: &[&str]) -> TermKind {
+    if index == 0 {
+        return TermKind::Commands;
+    }
+    let Some(command) = command::command_name(command) else {
+        return TermKind::Nothing;
+    };
+    match (command, index) {
+        (CommandName::Open | CommandName::ForceOpen | CommandName::Edit | CommandName::Save, 1) => TermKind::Path,
+        (CommandName::Config, 1) => TermKind::ConfigKey,
+        (CommandName::Delete | CommandName::Insert, 1) => TermKind::Words(&["column", "row"]),
+        (CommandName::Insert, 2) => TermKind::Words(&["post"]),
+        (CommandName::Fill, 1) => TermKind::Words(&["down", "right"]),
+        (CommandName::Filter, 1) => TermKind::Words(&["clear"]),
+        (CommandName::Freeze, 1) => TermKind::Words(&["off"]),
+        (CommandName::Replace, 3) => TermKind::Words(&["column", "row"]),
+        (CommandName::Sort, 1) => TermKind::Words(&["by", "header", "row"]),
+        (CommandName::Sort, _) if !before.contains(&"by") => TermKind::Words(&["by", "header"]),
+        _ => TermKind::Nothing
+    }
+}
+
+/// Get the completions of the last word of the start of a command line (the text before the cursor)
+/// Command names and aliases, subcommands, config keys, and paths (for `open` and `save`) are completed
+pub fn complete(line: &str, config: &ConfigData) -> Completions {
+    let (mut tokens, _) = command::tokenize(line);
+    // The word being completed: the last term if the cursor is at its end, or else a new one
+    let (mut start, mut word, quote) = match tokens.pop() {
+        Some(token) if token.end == line.len() => (token.start, token.text, token.quote),
+        Some(token) => {
+            tokens.push(token);
+            (line.len(), String::new(), None)
+        }
+        None => (line.len(), String::new(), None)
+    };
+    let terms: Vec<&str> = tokens.iter().map(|t| t.text.as_str()).collect();
+    let index = terms.len();
+    // Remove leading ':' (for vim users)
+    let command = terms.first().map(|t| t.strip_prefix(':').unwrap_or(t)).unwrap_or_default();
+    if index == 0 && quote.is_none() && word.starts_with(':') {
+        start += 1;
+        word.remove(0);
+    }
+    let candidates = match term_kind(command, index, &terms) {
+        TermKind::Commands => command::COMMANDS.iter().map(|(name, _)| name.to_string()).collect(),
+        TermKind::Words(words) => words.iter().map(|w| w.to_string()).collect(),
+        TermKind::ConfigKey => config.keys(),
+        TermKind::Path => complete_path(&word),
+        TermKind::Nothing => Vec::new()
+    };
+    let mut candidates: Vec<String> = candidates.into_iter()
+        .filter(|c| c.starts_with(&word))
+        .map(|c| quote_word(&c, quote))
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+    Completions { start, candidates }
+}
+
+/// Quote a completed word if it was started with a quote or needs one (see command::tokenize)
+/// Directories are left unclosed, to go on into them
+fn quote_word(word: &str, quote: Option<char>) -> String {
+    let needed = word.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'');
+    let quote = quote.or(needed.then_some('"'));
+    let Some(quote) = quote else {
+        return word.to_string();
+    };
+    let inner = if quote == '"' { word.replace('\\', "\\\\").replace('"', "\\\"") } else { word.to_string() };
+    let close = if word.ends_with(['/', std::path::MAIN_SEPARATOR]) { "" } else { &quote.to_string() };
+    format!("{}{}{}", quote, inner, close)
+}
+
+/// Get the paths starting with a partial path (directories end with a `/`; hidden files are only included if the name starts with a `.`)
+fn complete_path(word: &str) -> Vec<String> {
+    let (dir, name) = match word.rfind(['/', std::path::MAIN_SEPARATOR]) {
+        Some(i) => word.split_at(i + 1),
+        None => ("", word)
+    };
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { Path::new(".") } else { Path::new(dir) }) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let filename = entry.file_name().to_string_lossy().to_string();
+            if !filename.starts_with(name) || (filename.starts_with('.') && !name.starts_with('.')) {
+                return None;
+            }
+            let isdir = entry.path().is_dir();
+            Some(format!("{}{}{}", dir, filename, if isdir { "/" } else { "" }))
+        })
+        .collect()
+}
+
+/// Get the longest start shared by every candidate
+pub fn common_prefix(candidates: &[String]) -> &str {
+    let Some(first) = candidates.first() else {
+        return "";
+    };
+    let mut len = first.len();
+    for candidate in &candidates[1..] {
+        len = first.char_indices()
+            .zip(candidate.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(len.min(candidate.len()), |((i, _), _)| i.min(len));
+    }
+    &first[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates(line: &str) -> (usize, Vec<String>) {
+        let Completions { start, candidates } = complete(line, &ConfigData::with_defaults());
+        (start, candidates)
+    }
+
+    #[test]
+    fn completes_commands_and_terms() {
+        assert_eq!(candidates("fi"), (0, vec!["fill".to_string(), "filter".to_string()]));
+        assert_eq!(candidates(":und"), (1, vec!["undo".to_string(), "undolist".to_string()]));
+        assert_eq!(candidates("delete c"), (7, vec!["column".to_string()]));
+        assert_eq!(candidates("sort 1 "), (7, vec!["by".to_string(), "header".to_string()]));
+        assert_eq!(candidates("sort by 1 "), (10, Vec::new()));
+        assert_eq!(candidates("config hist"), (7, vec!["historysize".to_string()]));
+        assert_eq!(candidates("nope "), (5, Vec::new()));
+        // Only the word ending at the cursor is completed
+        assert_eq!(candidates("fill").1, vec!["fill".to_string()]);
+    }
+
+    #[test]
+    fn completes_paths_in_quotes() {
+        let dir = std::env::temp_dir().join(format!("sheatfish_completion_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub dir")).unwrap();
+        fs::write(dir.join("data.csv"), "").unwrap();
+        fs::write(dir.join(".hidden.csv"), "").unwrap();
+        let dirtext = format!("{}/", dir.to_string_lossy());
+        let (start, found) = candidates(&format!("open {}", dirtext));
+        assert_eq!(start, 5);
+        assert_eq!(found, vec![format!("\"{}sub dir/", dirtext), format!("{}data.csv", dirtext)]);
+        assert_eq!(candidates(&format!("save '{}d", dirtext)).1, vec![format!("'{}data.csv'", dirtext)]);
+        assert_eq!(candidates(&format!("open {}.", dirtext)).1, vec![format!("{}.hidden.csv", dirtext)]);
+    }
+
+    #[test]
+    fn quotes_words_when_needed() {
+        assert_eq!(quote_word("data.csv", None), "data.csv");
+        assert_eq!(quote_word("my data.csv", None), "\"my data.csv\"");
+        assert_eq!(quote_word("say \"hi\"", None), "\"say \\\"hi\\\"\"");
+        assert_eq!(quote_word("a\\b c", None), "\"a\\\\b c\"");
+        assert_eq!(quote_word("data.csv", Some('\'')), "'data.csv'");
+        assert_eq!(quote_word("my dir/", None), "\"my dir/");
+    }
+
+    #[test]
+    fn finds_common_prefixes() {
+        let words = |list: &[&str]| list.iter().map(|w| w.to_string()).collect::<Vec<String>>();
+        assert_eq!(common_prefix(&[]), "");
+        assert_eq!(common_prefix(&words(&["undo"])), "undo");
+        assert_eq!(common_prefix(&words(&["undo", "undolist", "unfreeze"])), "un");
+        assert_eq!(common_prefix(&words(&["filter", "fill"])), "fil");
+        assert_eq!(common_prefix(&words(&["fill", "filter"])), "fil");
+        assert_eq!(common_prefix(&words(&["é1", "é2"])), "é");
+        assert_eq!(common_prefix(&words(&["open", "save"])), "");
+    }
+}
@@ -3,63 +3,106 @@ use std::fs;
 use std::path::PathBuf;
 use homedir::my_home;
 
+/// The version of the config file format, saved as `configversion` (files without it are from before
+/// views fit the terminal, when `viewcellswidth` and `viewcellsheight` were saved as 10 by default)
+const CONFIG_VERSION: i32 = 1;
+
+/// Max number of undo steps kept, unless set by the `historysize` config value
+pub const DEFAULT_HISTORY_SIZE: i32 = 100;
+
 /// Stores the config data
 pub struct ConfigData {
     datamap: HashMap<String, i32>,
-    savepath: PathBuf,
+    savepath: Option<PathBuf> // None if never loaded from or saved to a file (see with_defaults)
 }
 
 impl ConfigData {
-    /// Create a new ConfigData with the default parameters
+    /// Create a new ConfigData with the default parameters, loaded from the config file if there is one
     pub fn new() -> ConfigData {
         let mut savepath = match my_home() {
             Ok(Some(homepath)) => homepath,
             _ => PathBuf::from("./")
         };
         savepath.push(".sheatfish_config.csv");
-        // Default config
-        let mut res = ConfigData {
+        let mut res = ConfigData { savepath: Some(savepath), ..ConfigData::with_defaults() };
+        // Try to load from the file
+        res.try_load_from_file();
+        res
+    }
+
+    /// Create a ConfigData with the default parameters, never loaded from or saved to a file
+    pub fn with_defaults() -> ConfigData {
+        ConfigData {
             datamap: HashMap::from([
+                ("configversion".to_string(), CONFIG_VERSION),
                 ("maxcellwidth".to_string(), 5),
                 ("vimmode".to_string(), 0),
-                ("viewcellswidth".to_string(), 10),
-                ("viewcellsheight".to_string(), 10),
-                ("historysize".to_string(), 100)
+                ("viewcellswidth".to_string(), 0),
+                ("viewcellsheight".to_string(), 0),
+                ("historysize".to_string(), DEFAULT_HISTORY_SIZE),
+                ("osc52".to_string(), 1),
+                ("freezerows".to_string(), 0),
+                ("freezecolumns".to_string(), 0),
+                ("autofitmax".to_string(), 30),
+                ("undofile".to_string(), 0)
             ]),
-            savepath
-        };
-        // Try to load from the file
-        res.try_load_from_file();
-        res
+            savepath: None
+        }
     }
 
-    /// Load from a file
+    /// Load from a file (saving it back only if it had to be migrated to the current version)
     pub fn try_load_from_file(&mut self) {
-        let read_res = fs::read_to_string(&self.savepath).unwrap_or(String::new());
-        let contents = read_res.replace("\r\n", "\n").replace("\r", "\n");
+        let Some(savepath) = &self.savepath else {
+            return;
+        };
+        let read_res = fs::read_to_string(savepath).unwrap_or_default();
+        if self.load_text(&read_res) {
+            self.try_save_to_file();
+        }
+    }
+
+    /// Load the contents of a config file, returning whether they were migrated from an older version
+    fn load_text(&mut self, text: &str) -> bool {
+        let contents = text.replace("\r\n", "\n").replace("\r", "\n");
+        let mut loaded: HashMap<String, i32> = HashMap::new();
         for configline in contents.split('\n').filter(|x| !x.trim().is_empty()) {
             // Parse: "key, value"
             let mut splitted = configline.split(',');
             if let Some(key) = splitted.next() {
                 if let Some(val) = splitted.next() {
-                    self.set_value(
-                        key.trim(),
-                        val.trim().parse::<i32>().unwrap_or(0)
-                    );
+                    loaded.insert(key.trim().to_string(), val.trim().parse::<i32>().unwrap_or(0));
                 }
             }
         }
+        if loaded.is_empty() {
+            return false;
+        }
+        // A file without a version is from before views fit the terminal, so its view size of 10 is the old default
+        let migrate = !loaded.contains_key("configversion");
+        if migrate {
+            for key in ["viewcellswidth", "viewcellsheight"] {
+                if loaded.get(key) == Some(&10) {
+                    loaded.insert(key.to_string(), 0);
+                }
+            }
+            loaded.insert("configversion".to_string(), CONFIG_VERSION);
+        }
+        self.datamap.extend(loaded);
+        migrate
     }
 
     /// Save to a file
     pub fn try_save_to_file(&self) {
+        let Some(savepath) = &self.savepath else {
+            return;
+        };
         // Generate the result
         let mut res = String::new();
         for (key, value) in &self.datamap {
             res.push_str(&format!("{}, {}\n", key, value));
         }
         // Open the file
-        let _ = fs::write(&self.savepath, res);
+        let _ = fs::write(savepath, res);
     }
 
     /// Get the config value of a string key
@@ -73,6 +116,13 @@ impl ConfigData {
         self.try_save_to_file();
     }
 
+    /// Get the config keys, sorted
+    pub fn keys(&self) -> Vec<String> {
+        let mut res: Vec<String> = self.datamap.keys().cloned().collect();
+        res.sort();
+        res
+    }
+
     /// Get the display of the config data
     pub fn display(&self) -> String {
         let mut res = String::new();
@@ -82,3 +132,32 @@ impl ConfigData {
         res
     }
 }
+
+impl Default for ConfigData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_the_old_default_view_size() {
+        let mut config = ConfigData::with_defaults();
+        assert!(config.load_text("maxcellwidth, 7\r\nviewcellswidth, 10\r\nviewcellsheight, 12\r\n"));
+        assert_eq!(config.get_value("viewcellswidth"), Some(0));
+        assert_eq!(config.get_value("viewcellsheight"), Some(12));
+        assert_eq!(config.get_value("maxcellwidth"), Some(7));
+        assert_eq!(config.get_value("configversion"), Some(CONFIG_VERSION));
+    }
+
+    #[test]
+    fn keeps_a_chosen_view_size() {
+        let mut config = ConfigData::with_defaults();
+        assert!(!config.load_text("configversion, 1\nviewcellswidth, 10\n"));
+        assert_eq!(config.get_value("viewcellswidth"), Some(10));
+        assert!(!config.load_text(""));
+    }
+}
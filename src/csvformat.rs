@@ -0,0 +1,357 @@
+use std::{ fmt, iter::Peekable, str::Chars };
+
+/// An error found while parsing delimited text (line and column are 1-indexed)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// Whether a character is whitespace that may surround a field (but is not the delimiter itself)
+fn is_padding(c: char, delimiter: char) -> bool {
+    c != delimiter && (c == ' ' || c == '\t')
+}
+
+/// Skip a line ending (`\n`, `\r\n` or a lone `\r`) if one is next, returning whether there was one
+fn skip_line_end(chars: &mut Peekable<Chars>) -> bool {
+    match chars.peek() {
+        Some('\n') => {
+            chars.next();
+            true
+        }
+        Some('\r') => {
+            chars.next();
+            if chars.peek() == Some(&'\n') {
+                chars.next();
+            }
+            true
+        }
+        _ => false
+    }
+}
+
+/// Parse delimited text (RFC 4180) into rows of fields
+/// Unquoted fields are trimmed, quoted fields are kept exactly (including any line endings in them), and blank lines are skipped
+/// Lines can end with `\n`, `\r\n` or `\r`
+pub fn parse(input: &str, delimiter: char) -> Result<Vec<Vec<String>>, ParseError> {
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    let mut row: Vec<String> = Vec::new();
+    let mut field = String::new();
+    // Whether the current row contains a quoted field (so a lone "" is not a blank line)
+    let mut row_quoted = false;
+    let mut chars = input.chars().peekable();
+    let (mut line, mut column) = (1, 0);
+    loop {
+        // Start of a field: skip leading padding
+        while let Some(&c) = chars.peek() {
+            if !is_padding(c, delimiter) {
+                break;
+            }
+            chars.next();
+            column += 1;
+        }
+        let mut ended_line = false;
+        if chars.peek() == Some(&'"') {
+            // Quoted field: read until the closing quote, allowing "" as an escaped quote
+            chars.next();
+            column += 1;
+            row_quoted = true;
+            let (open_line, open_column) = (line, column);
+            loop {
+                match chars.next() {
+                    None => {
+                        return Err(ParseError {
+                            line: open_line,
+                            column: open_column,
+                            message: "unterminated quoted field".to_string()
+                        });
+                    }
+                    Some('"') => {
+                        column += 1;
+                        if chars.peek() == Some(&'"') {
+                            chars.next();
+                            column += 1;
+                            field.push('"');
+                        } else {
+                            break;
+                        }
+                    }
+                    Some('\n') => {
+                        line += 1;
+                        column = 0;
+                        field.push('\n');
+                    }
+                    Some(c) => {
+                        column += 1;
+                        field.push(c);
+                    }
+                }
+            }
+            // After the closing quote, only padding may come before the delimiter
+            loop {
+                match chars.peek() {
+                    Some(&c) if is_padding(c, delimiter) => {
+                        chars.next();
+                        column += 1;
+                    }
+                    Some(&c) if c == delimiter => {
+                        chars.next();
+                        column += 1;
+                        break;
+                    }
+                    Some('\n' | '\r') => {
+                        skip_line_end(&mut chars);
+                        ended_line = true;
+                        break;
+                    }
+                    None => {
+                        ended_line = true;
+                        break;
+                    }
+                    Some(_) => {
+                        return Err(ParseError {
+                            line,
+                            column: column + 1,
+                            message: "unexpected character after closing quote".to_string()
+                        });
+                    }
+                }
+            }
+            row.push(field.clone());
+        } else {
+            // Unquoted field: read until the delimiter or the end of the line
+            loop {
+                match chars.peek() {
+                    Some(&c) if c == delimiter => {
+                        chars.next();
+                        column += 1;
+                        break;
+                    }
+                    Some('\n' | '\r') => {
+                        skip_line_end(&mut chars);
+                        ended_line = true;
+                        break;
+                    }
+                    None => {
+                        ended_line = true;
+                        break;
+                    }
+                    Some('"') => {
+                        return Err(ParseError {
+                            line,
+                            column: column + 1,
+                            message: "unexpected quote in unquoted field".to_string()
+                        });
+                    }
+                    Some(&c) => {
+                        chars.next();
+                        column += 1;
+                        field.push(c);
+                    }
+                }
+            }
+            row.push(field.trim_end_matches(|c| is_padding(c, delimiter)).to_string());
+        }
+        field.clear();
+        if ended_line {
+            // Finish the row, skipping blank lines
+            let blank = !row_quoted && row.len() == 1 && row[0].is_empty();
+            if !blank {
+                rows.push(row.clone());
+            }
+            row.clear();
+            row_quoted = false;
+            line += 1;
+            column = 0;
+            if chars.peek().is_none() {
+                break;
+            }
+        }
+    }
+    Ok(rows)
+}
+
+/// Format a single field, quoting it only if required
+pub fn format_field(value: &str, delimiter: char) -> String {
+    let needs_quotes = value.contains(delimiter)
+        || value.contains('"')
+        || value.contains('\n')
+        || value.contains('\r')
+        || value.starts_with(|c| is_padding(c, delimiter))
+        || value.ends_with(|c| is_padding(c, delimiter));
+    if needs_quotes {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Generate delimited text (RFC 4180) from rows of fields
+pub fn generate(rows: &[Vec<String>], delimiter: char) -> String {
+    let mut res = String::new();
+    for row in rows {
+        let fields: Vec<String> = row.iter().map(|v| format_field(v, delimiter)).collect();
+        res.push_str(&fields.join(&delimiter.to_string()));
+        res.push('\n');
+    }
+    res
+}
+
+/// Delimiters that can be detected, in order of preference when sniffing
+const SNIFF_CANDIDATES: [char; 4] = [',', '\t', ';', '|'];
+/// Number of lines looked at when sniffing the delimiter
+const SNIFF_LINES: usize = 10;
+
+/// Get the delimiter implied by a file's extension, if any (.tsv and .psv)
+pub fn delimiter_from_extension(path: &str) -> Option<char> {
+    let lowercase = path.to_lowercase();
+    if lowercase.ends_with(".tsv") || lowercase.ends_with(".tab") {
+        Some('\t')
+    } else if lowercase.ends_with(".psv") {
+        Some('|')
+    } else {
+        None
+    }
+}
+
+/// Guess the delimiter of delimited text from its first lines (defaulting to a comma)
+/// A candidate that appears the same nonzero number of times on every line is preferred
+pub fn sniff_delimiter(input: &str) -> char {
+    // Count the candidates outside quotes on each line
+    let mut counts: Vec<[usize; SNIFF_CANDIDATES.len()]> = Vec::new();
+    let mut linecounts = [0; SNIFF_CANDIDATES.len()];
+    let mut in_quotes = false;
+    for c in input.chars() {
+        if c == '"' {
+            in_quotes = !in_quotes;
+        } else if (c == '\n' || c == '\r') && !in_quotes {
+            if linecounts.iter().any(|&n| n > 0) {
+                counts.push(linecounts);
+                if counts.len() >= SNIFF_LINES {
+                    break;
+                }
+            }
+            linecounts = [0; SNIFF_CANDIDATES.len()];
+        } else if !in_quotes {
+            if let Some(i) = SNIFF_CANDIDATES.iter().position(|&d| d == c) {
+                linecounts[i] += 1;
+            }
+        }
+    }
+    if linecounts.iter().any(|&n| n > 0) && counts.len() < SNIFF_LINES {
+        counts.push(linecounts);
+    }
+    // Score each candidate: consistent candidates first, then by total count
+    let mut best: Option<(bool, usize, char)> = None;
+    for (i, &candidate) in SNIFF_CANDIDATES.iter().enumerate() {
+        let total: usize = counts.iter().map(|line| line[i]).sum();
+        if total == 0 {
+            continue;
+        }
+        let consistent = counts.iter().all(|line| line[i] == counts[0][i]);
+        if best.is_none_or(|(bconsistent, btotal, _)| (consistent, total) > (bconsistent, btotal)) {
+            best = Some((consistent, total, candidate));
+        }
+    }
+    best.map(|(_, _, candidate)| candidate).unwrap_or(',')
+}
+
+/// Parse a delimiter from its name or literal character (ex. "tab", "semicolon", "|")
+pub fn parse_delimiter(name: &str) -> Option<char> {
+    match name {
+        "comma" | "csv" => Some(','),
+        "tab" | "tsv" | "\\t" => Some('\t'),
+        "semicolon" | "semi" => Some(';'),
+        "pipe" | "psv" => Some('|'),
+        "space" => Some(' '),
+        _ => {
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if c != '"' && c != '\n' => Some(c),
+                _ => None
+            }
+        }
+    }
+}
+
+/// Get the display name of a delimiter
+pub fn delimiter_name(delimiter: char) -> String {
+    match delimiter {
+        ',' => "comma".to_string(),
+        '\t' => "tab".to_string(),
+        ';' => "semicolon".to_string(),
+        '|' => "pipe".to_string(),
+        ' ' => "space".to_string(),
+        c => format!("'{}'", c)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(lines: &[&[&str]]) -> Vec<Vec<String>> {
+        lines.iter().map(|line| line.iter().map(|v| v.to_string()).collect()).collect()
+    }
+
+    #[test]
+    fn parses_plain_fields() {
+        assert_eq!(parse("a,b,c\n1, 2 ,3\n", ',').unwrap(), rows(&[&["a", "b", "c"], &["1", "2", "3"]]));
+        assert_eq!(parse("a,,c", ',').unwrap(), rows(&[&["a", "", "c"]]));
+        assert_eq!(parse("a;b\n\n\nc;d", ';').unwrap(), rows(&[&["a", "b"], &["c", "d"]]));
+    }
+
+    #[test]
+    fn parses_quoted_fields() {
+        assert_eq!(parse("\"a, b\",\"say \"\"hi\"\"\"\n", ',').unwrap(), rows(&[&["a, b", "say \"hi\""]]));
+        assert_eq!(parse(" \" padded \" ,x", ',').unwrap(), rows(&[&[" padded ", "x"]]));
+        assert_eq!(parse("\"\"\n", ',').unwrap(), rows(&[&[""]]));
+    }
+
+    #[test]
+    fn parses_embedded_newlines() {
+        assert_eq!(parse("\"line 1\nline 2\",b\nc,d", ',').unwrap(), rows(&[&["line 1\nline 2", "b"], &["c", "d"]]));
+        assert_eq!(parse("\"x\r\ny\",\"z\r\"\r\n", ',').unwrap(), rows(&[&["x\r\ny", "z\r"]]));
+    }
+
+    #[test]
+    fn parses_line_endings() {
+        let expected = rows(&[&["a", "b"], &["c", "d"]]);
+        assert_eq!(parse("a,b\r\nc,d\r\n", ',').unwrap(), expected);
+        assert_eq!(parse("a,b\rc,d", ',').unwrap(), expected);
+        assert_eq!(parse("\"a\",\"b\"\r\n\"c\",\"d\"", ',').unwrap(), expected);
+        assert_eq!(parse("a,b\r\n\r\nc,d", ',').unwrap(), expected);
+    }
+
+    #[test]
+    fn rejects_misplaced_quotes() {
+        let err = parse("a,b\nc,d\"e\n", ',').unwrap_err();
+        assert_eq!((err.line, err.column, err.message.as_str()), (2, 4, "unexpected quote in unquoted field"));
+        let err = parse("\"ab\"c", ',').unwrap_err();
+        assert_eq!(err.message, "unexpected character after closing quote");
+        let err = parse("a,\"b\nc", ',').unwrap_err();
+        assert_eq!((err.line, err.column, err.message.as_str()), (1, 3, "unterminated quoted field"));
+    }
+
+    #[test]
+    fn round_trips() {
+        let data = rows(&[&["plain", "with, comma", " padded"], &["quote \"", "cr\rlf\r\n", ""]]);
+        assert_eq!(parse(&generate(&data, ','), ',').unwrap(), data);
+        assert_eq!(parse(&generate(&data, '\t'), '\t').unwrap(), data);
+    }
+
+    #[test]
+    fn sniffs_delimiters() {
+        assert_eq!(sniff_delimiter("a;b;c\r\nd;e;f\r\n"), ';');
+        assert_eq!(sniff_delimiter("a\tb,c\nd\te\n"), '\t');
+        assert_eq!(sniff_delimiter("\"a,b\"|c\nd|e"), '|');
+        assert_eq!(sniff_delimiter("abc"), ',');
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::{ HashMap, HashSet, VecDeque };
+use std::ops::{ Range, RangeInclusive };
+use crate::formula::Precedent;
+
+/// Rows per bucket of the range index
+const BUCKET_ROWS: usize = 64;
+/// Ranges spanning more buckets than this are kept in a single list, checked for every cell
+const MAX_RANGE_BUCKETS: usize = 1024;
+
+/// A range and a formula cell reading it
+type RangeDependent = (Precedent, (usize, usize));
+
+/// The formula cells to recompute after a change
+pub struct RecalcOrder {
+    pub order: Vec<(usize, usize)>, // Evaluation order (each cell after everything it reads)
+    pub circular: Vec<(usize, usize)> // Cells in or downstream of a circular reference
+}
+
+/// Tracks which cells each formula reads, so a change only recomputes the formulas depending on it
+#[derive(Clone, Default)]
+pub struct DependencyGraph {
+    precedents: HashMap<(usize, usize), Vec<Precedent>>, // Formula cell -> what it reads
+    cell_dependents: HashMap<(usize, usize), HashSet<(usize, usize)>>, // Cell -> formula cells reading it directly
+    range_dependents: HashMap<usize, Vec<RangeDependent>>, // Row bucket -> ranges over it and the formula cells reading them
+    wide_range_dependents: Vec<RangeDependent> // Ranges over too many buckets -> formula cells reading them
+}
+
+/// Get the row buckets a range spans, or None if it spans too many to index
+fn range_buckets(start: (usize, usize), end: (usize, usize)) -> Option<RangeInclusive<usize>> {
+    let (first, last) = (start.0 / BUCKET_ROWS, end.0 / BUCKET_ROWS);
+    (last - first < MAX_RANGE_BUCKETS).then_some(first..=last)
+}
+
+/// Whether a precedent reads any cell of a rectangle
+fn intersects(precedent: &Precedent, rows: &Range<usize>, cols: &Range<usize>) -> bool {
+    let (start, end) = match *precedent {
+        Precedent::Cell(coords) => (coords, coords),
+        Precedent::Range(start, end) => (start, end)
+    };
+    start.0 < rows.end && end.0 >= rows.start && start.1 < cols.end && end.1 >= cols.start
+}
+
+impl DependencyGraph {
+    /// Remove all formulas
+    pub fn clear(&mut self) {
+        self.precedents.clear();
+        self.cell_dependents.clear();
+        self.range_dependents.clear();
+        self.wide_range_dependents.clear();
+    }
+    /// Remove a formula cell and everything it reads
+    pub fn remove(&mut self, cell: (usize, usize)) {
+        let Some(old) = self.precedents.remove(&cell) else {
+            return;
+        };
+        for precedent in old {
+            match precedent {
+                Precedent::Cell(coords) => {
+                    if let Some(dependents) = self.cell_dependents.get_mut(&coords) {
+                        dependents.remove(&cell);
+                        if dependents.is_empty() {
+                            self.cell_dependents.remove(&coords);
+                        }
+                    }
+                }
+                Precedent::Range(start, end) => match range_buckets(start, end) {
+                    Some(buckets) => {
+                        for bucket in buckets {
+                            if let Some(ranges) = self.range_dependents.get_mut(&bucket) {
+                                ranges.retain(|(_, dependent)| *dependent != cell);
+                                if ranges.is_empty() {
+                                    self.range_dependents.remove(&bucket);
+                                }
+                            }
+                        }
+                    }
+                    None => self.wide_range_dependents.retain(|(_, dependent)| *dependent != cell)
+                }
+            }
+        }
+    }
+    /// Set what a formula cell reads (replacing what it read before)
+    pub fn set_precedents(&mut self, cell: (usize, usize), precedents: Vec<Precedent>) {
+        self.remove(cell);
+        for precedent in &precedents {
+            match precedent {
+                Precedent::Cell(coords) => {
+                    self.cell_dependents.entry(*coords).or_default().insert(cell);
+                }
+                Precedent::Range(start, end) => match range_buckets(*start, *end) {
+                    Some(buckets) => {
+                        for bucket in buckets {
+                            self.range_dependents.entry(bucket).or_default().push((*precedent, cell));
+                        }
+                    }
+                    None => self.wide_range_dependents.push((*precedent, cell))
+                }
+            }
+        }
+        self.precedents.insert(cell, precedents);
+    }
+    /// Get the formula cells directly reading a cell
+    pub fn dependents(&self, cell: (usize, usize)) -> HashSet<(usize, usize)> {
+        let mut res = self.cell_dependents.get(&cell).cloned().unwrap_or_default();
+        let bucket = self.range_dependents.get(&(cell.0 / BUCKET_ROWS)).into_iter().flatten();
+        for (range, dependent) in bucket.chain(&self.wide_range_dependents) {
+            if let Precedent::Range(start, end) = range {
+                if (start.0..=end.0).contains(&cell.0) && (start.1..=end.1).contains(&cell.1) {
+                    res.insert(*dependent);
+                }
+            }
+        }
+        res
+    }
+    /// Get the formula cells to recompute after the given cells changed
+    pub fn recalc_order(&self, changed: &[(usize, usize)]) -> RecalcOrder {
+        // Find everything transitively depending on the changed cells
+        let mut affected: HashSet<(usize, usize)> = changed.iter().copied().collect();
+        let mut queue: VecDeque<(usize, usize)> = changed.iter().copied().collect();
+        let mut edges: HashMap<(usize, usize), HashSet<(usize, usize)>> = HashMap::new();
+        while let Some(cell) = queue.pop_front() {
+            let dependents = self.dependents(cell);
+            for dependent in &dependents {
+                if affected.insert(*dependent) {
+                    queue.push_back(*dependent);
+                }
+            }
+            edges.insert(cell, dependents);
+        }
+        // Topologically sort the affected cells (Kahn's algorithm)
+        let mut indegree: HashMap<(usize, usize), usize> = affected.iter().map(|cell| (*cell, 0)).collect();
+        for dependents in edges.values() {
+            for dependent in dependents {
+                *indegree.entry(*dependent).or_default() += 1;
+            }
+        }
+        let mut ready: VecDeque<(usize, usize)> = indegree.iter().filter(|(_, n)| **n == 0).map(|(cell, _)| *cell).collect();
+        let mut order: Vec<(usize, usize)> = Vec::new();
+        while let Some(cell) = ready.pop_front() {
+            if self.precedents.contains_key(&cell) {
+                order.push(cell);
+            }
+            for dependent in &edges[&cell] {
+                let n = indegree.get_mut(dependent).expect("affected cell has an indegree");
+                *n -= 1;
+                if *n == 0 {
+                    ready.push_back(*dependent);
+                }
+            }
+        }
+        // Anything never reached is part of a cycle, or reads one
+        let circular: Vec<(usize, usize)> = indegree.into_iter().filter(|(_, n)| *n > 0).map(|(cell, _)| cell).collect();
+        RecalcOrder { order, circular }
+    }
+    /// Get the formula cells reading any cell of a rectangle (rows, columns)
+    pub fn readers(&self, rows: Range<usize>, cols: Range<usize>) -> Vec<(usize, usize)> {
+        self.precedents.iter()
+            .filter(|(_, precedents)| precedents.iter().any(|p| intersects(p, &rows, &cols)))
+            .map(|(cell, _)| *cell)
+            .collect()
+    }
+    /// Move formula cells to new coordinates (or remove them, for None), keeping what they read
+    pub fn remap(&mut self, moved: impl Fn((usize, usize)) -> Option<(usize, usize)>) {
+        let precedents = std::mem::take(&mut self.precedents);
+        self.clear();
+        for (cell, precedents) in precedents {
+            if let Some(newcell) = moved(cell) {
+                self.set_precedents(newcell, precedents);
+            }
+        }
+    }
+    /// Get all formula cells
+    pub fn formula_cells(&self) -> Vec<(usize, usize)> {
+        self.precedents.keys().copied().collect()
+    }
+}
@@ -0,0 +1,87 @@
+use std::fmt;
+use regex::Regex;
+use crate::formula;
+
+/// How a filter tests the values of its column
+#[derive(Clone, Debug)]
+pub enum Predicate {
+    Equals(String), // Is exactly the text
+    NotEquals(String), // Is not exactly the text
+    Contains(String), // Contains the text
+    Regex(Regex), // Matches the regular expression
+    Less(f64), // Is a number less than this
+    LessEqual(f64),
+    Greater(f64),
+    GreaterEqual(f64)
+}
+
+impl Predicate {
+    /// Parse a predicate from its operator and value (ex. `>=` and `10`, or `contains` and `fail`)
+    pub fn parse(operator: &str, value: &str) -> Result<Predicate, String> {
+        let number = || formula::parse_number(value.trim()).ok_or_else(|| format!("Expected a number after \"{}\".", operator));
+        match operator {
+            "=" | "==" | "is" => Ok(Predicate::Equals(value.to_string())),
+            "!=" | "<>" | "isnt" => Ok(Predicate::NotEquals(value.to_string())),
+            "contains" | "has" => Ok(Predicate::Contains(value.to_string())),
+            "~" | "regex" | "matches" => Regex::new(value).map(Predicate::Regex).map_err(|e| format!("Invalid regex: {}", e)),
+            "<" => Ok(Predicate::Less(number()?)),
+            "<=" => Ok(Predicate::LessEqual(number()?)),
+            ">" => Ok(Predicate::Greater(number()?)),
+            ">=" => Ok(Predicate::GreaterEqual(number()?)),
+            _ => Err(format!("Unknown filter operator \"{}\".", operator))
+        }
+    }
+    /// Whether a value passes (numeric comparisons fail for values that are not numbers)
+    pub fn matches(&self, value: &str) -> bool {
+        let number = formula::parse_number(value.trim());
+        match self {
+            Predicate::Equals(text) => value == text,
+            Predicate::NotEquals(text) => value != text,
+            Predicate::Contains(text) => value.contains(text.as_str()),
+            Predicate::Regex(re) => re.is_match(value),
+            Predicate::Less(n) => number.is_some_and(|v| v < *n),
+            Predicate::LessEqual(n) => number.is_some_and(|v| v <= *n),
+            Predicate::Greater(n) => number.is_some_and(|v| v > *n),
+            Predicate::GreaterEqual(n) => number.is_some_and(|v| v >= *n)
+        }
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Predicate::Equals(text) => write!(f, "= {}", text),
+            Predicate::NotEquals(text) => write!(f, "!= {}", text),
+            Predicate::Contains(text) => write!(f, "contains {}", text),
+            Predicate::Regex(re) => write!(f, "~ {}", re.as_str()),
+            Predicate::Less(n) => write!(f, "< {}", n),
+            Predicate::LessEqual(n) => write!(f, "<= {}", n),
+            Predicate::Greater(n) => write!(f, "> {}", n),
+            Predicate::GreaterEqual(n) => write!(f, ">= {}", n)
+        }
+    }
+}
+
+/// A condition on the values of a column; rows that do not pass are hidden
+#[derive(Clone, Debug)]
+pub struct Filter {
+    pub column: usize,
+    pub predicate: Predicate
+}
+
+impl Filter {
+    /// Parse the terms after `filter`: `{column} {operator} {value ...}` (the value may contain spaces)
+    pub fn parse(terms: &[&str]) -> Result<Filter, String> {
+        let [column, operator, value @ ..] = terms else {
+            return Err("Expected a column and a condition (ex. \"filter 2 = failed\").".to_string());
+        };
+        let column = column.parse::<usize>().map_err(|_| format!("Invalid filter column \"{}\".", column))?;
+        Ok(Filter { column, predicate: Predicate::parse(operator, &value.join(" "))? })
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "column {} {}", self.column, self.predicate)
+    }
+}
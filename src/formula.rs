@@ -0,0 +1,1053 @@
+use std::{ cmp::Ordering, fmt };
+
+/// An error value produced by evaluating a formula
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CellError {
+    Ref, // Reference to a cell that does not exist
+    DivZero, // Division by zero
+    Value, // Wrong type of value
+    Name, // Unknown function or name
+    Num, // Invalid number (ex. overflow)
+    Circular, // Circular reference
+    Parse // Malformed formula
+}
+
+impl CellError {
+    /// All error values, used when reading error literals in formulas
+    const ALL: [CellError; 7] = [
+        CellError::Ref,
+        CellError::DivZero,
+        CellError::Value,
+        CellError::Name,
+        CellError::Num,
+        CellError::Circular,
+        CellError::Parse
+    ];
+
+    /// Get the code displayed for this error
+    pub fn code(&self) -> &'static str {
+        match self {
+            CellError::Ref => "#REF!",
+            CellError::DivZero => "#DIV/0!",
+            CellError::Value => "#VALUE!",
+            CellError::Name => "#NAME?",
+            CellError::Num => "#NUM!",
+            CellError::Circular => "#CIRC!",
+            CellError::Parse => "#ERROR!"
+        }
+    }
+}
+
+/// A computed value of a cell
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Empty,
+    Number(f64),
+    Text(String),
+    Bool(bool),
+    Error(CellError)
+}
+
+impl Value {
+    /// Get the value of a literal (non-formula) cell
+    pub fn from_literal(text: &str) -> Value {
+        let trimmed = text.trim();
+        if trimmed.is_empty() {
+            return Value::Empty;
+        }
+        match parse_number(trimmed) {
+            Some(n) => Value::Number(n),
+            None => Value::Text(text.to_string())
+        }
+    }
+    /// Coerce to a number for arithmetic
+    fn to_number(&self) -> Result<f64, CellError> {
+        match self {
+            Value::Empty => Ok(0.0),
+            Value::Number(n) => Ok(*n),
+            Value::Bool(b) => Ok(if *b { 1.0 } else { 0.0 }),
+            Value::Text(s) => parse_number(s.trim()).ok_or(CellError::Value),
+            Value::Error(e) => Err(*e)
+        }
+    }
+    /// Coerce to a boolean for conditions
+    fn to_bool(&self) -> Result<bool, CellError> {
+        match self {
+            Value::Empty => Ok(false),
+            Value::Number(n) => Ok(*n != 0.0),
+            Value::Bool(b) => Ok(*b),
+            Value::Text(s) => match s.trim().to_uppercase().as_str() {
+                "TRUE" => Ok(true),
+                "FALSE" => Ok(false),
+                _ => Err(CellError::Value)
+            },
+            Value::Error(e) => Err(*e)
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Empty => Ok(()),
+            Value::Number(n) => write!(f, "{}", fmt_number(*n)),
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Bool(b) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            Value::Error(e) => write!(f, "{}", e.code())
+        }
+    }
+}
+
+/// Parse a finite number (rejecting words like "inf" and "NaN" that Rust would accept)
+pub(crate) fn parse_number(text: &str) -> Option<f64> {
+    if !text.chars().any(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    text.parse::<f64>().ok().filter(|n| n.is_finite())
+}
+
+/// Format a number for display (integers without a decimal point, others rounded to 10 places)
+pub fn fmt_number(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < 1e15 {
+        return format!("{}", n as i64);
+    }
+    let rounded = format!("{:.10}", n);
+    let trimmed = rounded.trim_end_matches('0').trim_end_matches('.');
+    if trimmed == "-0" {
+        "0".to_string()
+    } else {
+        trimmed.to_string()
+    }
+}
+
+/// Whether a cell's text is a formula (starts with '=')
+pub fn is_formula(text: &str) -> bool {
+    text.starts_with('=')
+}
+
+/// Get the A1-style name of a column (0 -> A, 25 -> Z, 26 -> AA)
+pub fn column_name(col: usize) -> String {
+    let mut res = String::new();
+    let mut n = col + 1;
+    while n > 0 {
+        let rem = (n - 1) % 26;
+        res.insert(0, (b'A' + rem as u8) as char);
+        n = (n - 1) / 26;
+    }
+    res
+}
+
+/// Parse an A1-style column name (case-insensitive) to its index
+fn parse_column_name(name: &str) -> Option<usize> {
+    if name.is_empty() || name.len() > 3 {
+        return None;
+    }
+    let mut res: usize = 0;
+    for c in name.chars() {
+        if !c.is_ascii_alphabetic() {
+            return None;
+        }
+        res = res * 26 + (c.to_ascii_uppercase() as u8 - b'A') as usize + 1;
+    }
+    Some(res - 1)
+}
+
+/// A reference to a cell, written in A1 style (ex. `B3`, `$B$3`) or coordinate style (ex. `(2, 1)`, `($2, $1)`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CellRef {
+    pub row: usize,
+    pub col: usize,
+    pub row_absolute: bool,
+    pub col_absolute: bool,
+    pub a1_style: bool
+}
+
+impl CellRef {
+    /// Get the (row, col) coordinates of the referenced cell
+    pub fn coords(&self) -> (usize, usize) {
+        (self.row, self.col)
+    }
+    /// Parse an A1-style reference (ex. `B3`, `$B$3`)
+    fn parse_a1(text: &str) -> Option<CellRef> {
+        let col_absolute = text.starts_with('$');
+        let rest = text.strip_prefix('$').unwrap_or(text);
+        let letters_end = rest.find(|c: char| !c.is_ascii_alphabetic())?;
+        let col = parse_column_name(&rest[..letters_end])?;
+        let rest = &rest[letters_end..];
+        let row_absolute = rest.starts_with('$');
+        let digits = rest.strip_prefix('$').unwrap_or(rest);
+        if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        let row = digits.parse::<usize>().ok()?.checked_sub(1)?;
+        Some(CellRef { row, col, row_absolute, col_absolute, a1_style: true })
+    }
+}
+
+impl fmt::Display for CellRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let colprefix = if self.col_absolute { "$" } else { "" };
+        let rowprefix = if self.row_absolute { "$" } else { "" };
+        if self.a1_style {
+            write!(f, "{}{}{}{}", colprefix, column_name(self.col), rowprefix, self.row + 1)
+        } else {
+            write!(f, "({}{}, {}{})", rowprefix, self.row, colprefix, self.col)
+        }
+    }
+}
+
+/// A binary operator
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge
+}
+
+/// The kind of a formula token
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenKind {
+    Number(f64),
+    Text(String),
+    Ident(String),
+    Ref(CellRef),
+    Error(CellError),
+    Op(BinOp),
+    LParen,
+    RParen,
+    Comma,
+    Colon
+}
+
+/// A formula token, with its byte span in the formula body (the text after '=')
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub start: usize,
+    pub end: usize
+}
+
+/// Try to read a coordinate-style reference like `(2, 1)` at the start of a string, returning it and its length
+/// Both coordinates must be integers (optionally absolute, with `$`), with nothing else between the parentheses
+fn read_coord_ref(text: &str) -> Option<(CellRef, usize)> {
+    let bytes = text.as_bytes();
+    let mut i = 0;
+    let skip_spaces = |i: &mut usize| {
+        while *i < bytes.len() && bytes[*i] == b' ' {
+            *i += 1;
+        }
+    };
+    let read_index = |i: &mut usize| -> Option<(usize, bool)> {
+        let absolute = *i < bytes.len() && bytes[*i] == b'$';
+        if absolute {
+            *i += 1;
+        }
+        let start = *i;
+        while *i < bytes.len() && bytes[*i].is_ascii_digit() {
+            *i += 1;
+        }
+        text[start..*i].parse::<usize>().ok().map(|n| (n, absolute))
+    };
+    if bytes.first() != Some(&b'(') {
+        return None;
+    }
+    i += 1;
+    skip_spaces(&mut i);
+    let (row, row_absolute) = read_index(&mut i)?;
+    skip_spaces(&mut i);
+    if bytes.get(i) != Some(&b',') {
+        return None;
+    }
+    i += 1;
+    skip_spaces(&mut i);
+    let (col, col_absolute) = read_index(&mut i)?;
+    skip_spaces(&mut i);
+    if bytes.get(i) != Some(&b')') {
+        return None;
+    }
+    Some((CellRef { row, col, row_absolute, col_absolute, a1_style: false }, i + 1))
+}
+
+/// Split a formula body (the text after '=') into tokens
+pub fn tokenize(body: &str) -> Result<Vec<Token>, CellError> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut i = 0;
+    while i < body.len() {
+        let rest = &body[i..];
+        let c = rest.chars().next().unwrap_or(' ');
+        let start = i;
+        let kind = match c {
+            ' ' | '\t' => {
+                i += 1;
+                continue;
+            }
+            '0'..='9' | '.' => {
+                // Number, with an optional exponent
+                let mut end = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+                if rest[end..].starts_with(['e', 'E']) {
+                    let exp = &rest[end + 1..];
+                    let exp_digits = exp.strip_prefix(['+', '-']).unwrap_or(exp);
+                    if exp_digits.starts_with(|c: char| c.is_ascii_digit()) {
+                        let sign_len = exp.len() - exp_digits.len();
+                        let digits_len = exp_digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(exp_digits.len());
+                        end += 1 + sign_len + digits_len;
+                    }
+                }
+                i += end;
+                TokenKind::Number(rest[..end].parse::<f64>().map_err(|_| CellError::Parse)?)
+            }
+            '"' => {
+                // String literal, with "" as an escaped quote
+                let mut text = String::new();
+                let mut chars = rest.char_indices().skip(1).peekable();
+                let mut end = None;
+                while let Some((j, ch)) = chars.next() {
+                    if ch == '"' {
+                        if let Some(&(_, '"')) = chars.peek() {
+                            chars.next();
+                            text.push('"');
+                        } else {
+                            end = Some(j + 1);
+                            break;
+                        }
+                    } else {
+                        text.push(ch);
+                    }
+                }
+                i += end.ok_or(CellError::Parse)?;
+                TokenKind::Text(text)
+            }
+            '#' => {
+                // Error literal (ex. #REF!)
+                let error = CellError::ALL.iter().find(|e| rest.to_uppercase().starts_with(e.code())).ok_or(CellError::Parse)?;
+                i += error.code().len();
+                TokenKind::Error(*error)
+            }
+            '(' => {
+                // Coordinate-style reference where a value is expected (so not a function call or grouping like `(1 + 2)`)
+                let operand_expected = matches!(
+                    tokens.last().map(|t| &t.kind),
+                    None | Some(TokenKind::Op(_) | TokenKind::LParen | TokenKind::Comma | TokenKind::Colon)
+                );
+                match read_coord_ref(rest) {
+                    Some((cellref, len)) if operand_expected => {
+                        i += len;
+                        TokenKind::Ref(cellref)
+                    }
+                    _ => {
+                        i += 1;
+                        TokenKind::LParen
+                    }
+                }
+            }
+            c if c.is_ascii_alphabetic() || c == '_' || c == '$' => {
+                // Identifier or A1-style reference
+                let end = rest.find(|c: char| !c.is_ascii_alphanumeric() && c != '_' && c != '.' && c != '$').unwrap_or(rest.len());
+                let word = &rest[..end];
+                i += end;
+                let is_call = body[i..].trim_start().starts_with('(');
+                match CellRef::parse_a1(word) {
+                    Some(cellref) if !is_call => TokenKind::Ref(cellref),
+                    _ => TokenKind::Ident(word.to_uppercase())
+                }
+            }
+            _ => {
+                // Punctuation and operators
+                let (kind, len) = if rest.starts_with("<=") {
+                    (TokenKind::Op(BinOp::Le), 2)
+                } else if rest.starts_with(">=") {
+                    (TokenKind::Op(BinOp::Ge), 2)
+                } else if rest.starts_with("<>") || rest.starts_with("!=") {
+                    (TokenKind::Op(BinOp::Ne), 2)
+                } else {
+                    let kind = match c {
+                        ')' => TokenKind::RParen,
+                        ',' => TokenKind::Comma,
+                        ':' => TokenKind::Colon,
+                        '+' => TokenKind::Op(BinOp::Add),
+                        '-' => TokenKind::Op(BinOp::Sub),
+                        '*' => TokenKind::Op(BinOp::Mul),
+                        '/' => TokenKind::Op(BinOp::Div),
+                        '^' => TokenKind::Op(BinOp::Pow),
+                        '&' => TokenKind::Op(BinOp::Concat),
+                        '=' => TokenKind::Op(BinOp::Eq),
+                        '<' => TokenKind::Op(BinOp::Lt),
+                        '>' => TokenKind::Op(BinOp::Gt),
+                        _ => return Err(CellError::Parse)
+                    };
+                    (kind, c.len_utf8())
+                };
+                i += len;
+                kind
+            }
+        };
+        tokens.push(Token { kind, start, end: i });
+    }
+    Ok(tokens)
+}
+
+/// A parsed formula expression
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(f64),
+    Text(String),
+    Bool(bool),
+    Error(CellError),
+    Ref(CellRef),
+    Range(CellRef, CellRef),
+    Neg(Box<Expr>),
+    Binary(BinOp, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>)
+}
+
+/// Recursive descent parser over formula tokens
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.pos).map(|t| &t.kind)
+    }
+    fn next(&mut self) -> Option<TokenKind> {
+        let res = self.tokens.get(self.pos).map(|t| t.kind.clone());
+        self.pos += 1;
+        res
+    }
+    fn expect(&mut self, kind: TokenKind) -> Result<(), CellError> {
+        if self.next() == Some(kind) {
+            Ok(())
+        } else {
+            Err(CellError::Parse)
+        }
+    }
+    /// Parse a binary level, given its operators and the next (higher precedence) level
+    fn binary(&mut self, ops: &[BinOp], next: fn(&mut Parser) -> Result<Expr, CellError>) -> Result<Expr, CellError> {
+        let mut lhs = next(self)?;
+        while let Some(TokenKind::Op(op)) = self.peek() {
+            let op = *op;
+            if !ops.contains(&op) {
+                break;
+            }
+            self.pos += 1;
+            let rhs = next(self)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+    fn comparison(&mut self) -> Result<Expr, CellError> {
+        self.binary(&[BinOp::Eq, BinOp::Ne, BinOp::Lt, BinOp::Le, BinOp::Gt, BinOp::Ge], Parser::concat)
+    }
+    fn concat(&mut self) -> Result<Expr, CellError> {
+        self.binary(&[BinOp::Concat], Parser::additive)
+    }
+    fn additive(&mut self) -> Result<Expr, CellError> {
+        self.binary(&[BinOp::Add, BinOp::Sub], Parser::multiplicative)
+    }
+    fn multiplicative(&mut self) -> Result<Expr, CellError> {
+        self.binary(&[BinOp::Mul, BinOp::Div], Parser::unary)
+    }
+    fn unary(&mut self) -> Result<Expr, CellError> {
+        match self.peek() {
+            Some(TokenKind::Op(BinOp::Sub)) => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.unary()?)))
+            }
+            Some(TokenKind::Op(BinOp::Add)) => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.power()
+        }
+    }
+    /// Exponents bind tighter than negation, as in math (so `-2^2` is `-(2^2)`, -4)
+    fn power(&mut self) -> Result<Expr, CellError> {
+        let base = self.primary()?;
+        if self.peek() == Some(&TokenKind::Op(BinOp::Pow)) {
+            // Right-associative
+            self.pos += 1;
+            let exponent = self.unary()?;
+            return Ok(Expr::Binary(BinOp::Pow, Box::new(base), Box::new(exponent)));
+        }
+        Ok(base)
+    }
+    fn primary(&mut self) -> Result<Expr, CellError> {
+        match self.next() {
+            Some(TokenKind::Number(n)) => Ok(Expr::Number(n)),
+            Some(TokenKind::Text(s)) => Ok(Expr::Text(s)),
+            Some(TokenKind::Error(e)) => Ok(Expr::Error(e)),
+            Some(TokenKind::Ref(start)) => {
+                if self.peek() == Some(&TokenKind::Colon) {
+                    self.pos += 1;
+                    match self.next() {
+                        Some(TokenKind::Ref(end)) => Ok(Expr::Range(start, end)),
+                        Some(TokenKind::Error(e)) => Ok(Expr::Error(e)),
+                        _ => Err(CellError::Parse)
+                    }
+                } else {
+                    Ok(Expr::Ref(start))
+                }
+            }
+            Some(TokenKind::LParen) => {
+                let inner = self.comparison()?;
+                self.expect(TokenKind::RParen)?;
+                Ok(inner)
+            }
+            Some(TokenKind::Ident(name)) => {
+                if self.peek() == Some(&TokenKind::LParen) {
+                    // Function call
+                    self.pos += 1;
+                    let mut args: Vec<Expr> = Vec::new();
+                    if self.peek() == Some(&TokenKind::RParen) {
+                        self.pos += 1;
+                        return Ok(Expr::Call(name, args));
+                    }
+                    loop {
+                        args.push(self.comparison()?);
+                        match self.next() {
+                            Some(TokenKind::Comma) => {}
+                            Some(TokenKind::RParen) => break,
+                            _ => return Err(CellError::Parse)
+                        }
+                    }
+                    Ok(Expr::Call(name, args))
+                } else {
+                    match name.as_str() {
+                        "TRUE" => Ok(Expr::Bool(true)),
+                        "FALSE" => Ok(Expr::Bool(false)),
+                        _ => Ok(Expr::Error(CellError::Name))
+                    }
+                }
+            }
+            _ => Err(CellError::Parse)
+        }
+    }
+}
+
+/// Parse a formula (with or without its leading '=')
+pub fn parse(text: &str) -> Result<Expr, CellError> {
+    let body = text.strip_prefix('=').unwrap_or(text);
+    let mut parser = Parser { tokens: tokenize(body)?, pos: 0 };
+    let expr = parser.comparison()?;
+    if parser.pos != parser.tokens.len() {
+        return Err(CellError::Parse);
+    }
+    Ok(expr)
+}
+
+/// Provides the values of referenced cells while evaluating a formula
+pub trait CellLookup {
+    /// Get the value of a cell, or None if it is out of bounds
+    fn value(&mut self, coords: (usize, usize)) -> Option<Value>;
+    /// Get the bounds of the sheet (y len, x len)
+    fn bounds(&self) -> (usize, usize);
+}
+
+/// An evaluated function argument
+enum Arg {
+    Scalar(Value),
+    Range(Vec<Value>)
+}
+
+/// Evaluate the cells of a range (clamped to the sheet bounds), in row-major order
+fn evaluate_range(start: &CellRef, end: &CellRef, lookup: &mut dyn CellLookup) -> Vec<Value> {
+    let bounds = lookup.bounds();
+    let (rowstart, rowend) = (start.row.min(end.row), start.row.max(end.row).min(bounds.0.saturating_sub(1)));
+    let (colstart, colend) = (start.col.min(end.col), start.col.max(end.col).min(bounds.1.saturating_sub(1)));
+    let mut res: Vec<Value> = Vec::new();
+    if bounds.0 == 0 || bounds.1 == 0 {
+        return res;
+    }
+    for row in rowstart..=rowend {
+        for col in colstart..=colend {
+            res.push(lookup.value((row, col)).unwrap_or(Value::Empty));
+        }
+    }
+    res
+}
+
+/// Evaluate an expression
+pub fn evaluate(expr: &Expr, lookup: &mut dyn CellLookup) -> Value {
+    match expr {
+        Expr::Number(n) => Value::Number(*n),
+        Expr::Text(s) => Value::Text(s.clone()),
+        Expr::Bool(b) => Value::Bool(*b),
+        Expr::Error(e) => Value::Error(*e),
+        Expr::Ref(cellref) => lookup.value(cellref.coords()).unwrap_or(Value::Error(CellError::Ref)),
+        Expr::Range(..) => Value::Error(CellError::Value), // A range is only valid as a function argument
+        Expr::Neg(inner) => match evaluate(inner, lookup).to_number() {
+            Ok(n) => Value::Number(-n),
+            Err(e) => Value::Error(e)
+        },
+        Expr::Binary(op, lhs, rhs) => {
+            let lhs = evaluate(lhs, lookup);
+            let rhs = evaluate(rhs, lookup);
+            evaluate_binary(*op, &lhs, &rhs)
+        }
+        Expr::Call(name, args) => evaluate_call(name, args, lookup)
+    }
+}
+
+/// Compare two values (numbers before text, text case-insensitively)
+fn compare_values(lhs: &Value, rhs: &Value) -> Ordering {
+    let rank = |v: &Value| match v {
+        Value::Empty | Value::Number(_) => 0,
+        Value::Text(_) => 1,
+        Value::Bool(_) => 2,
+        Value::Error(_) => 3
+    };
+    match (lhs, rhs) {
+        (Value::Text(a), Value::Text(b)) => a.to_lowercase().cmp(&b.to_lowercase()),
+        (Value::Text(a), Value::Empty) => a.to_lowercase().cmp(&String::new()),
+        (Value::Empty, Value::Text(b)) => String::new().cmp(&b.to_lowercase()),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Empty | Value::Number(_), Value::Empty | Value::Number(_)) => {
+            let a = lhs.to_number().unwrap_or(0.0);
+            let b = rhs.to_number().unwrap_or(0.0);
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal)
+        }
+        _ => rank(lhs).cmp(&rank(rhs))
+    }
+}
+
+/// Evaluate a binary operation on two values
+fn evaluate_binary(op: BinOp, lhs: &Value, rhs: &Value) -> Value {
+    // Errors propagate
+    if let Value::Error(e) = lhs {
+        return Value::Error(*e);
+    }
+    if let Value::Error(e) = rhs {
+        return Value::Error(*e);
+    }
+    match op {
+        BinOp::Concat => Value::Text(format!("{}{}", lhs, rhs)),
+        BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => {
+            let ord = compare_values(lhs, rhs);
+            Value::Bool(match op {
+                BinOp::Eq => ord == Ordering::Equal,
+                BinOp::Ne => ord != Ordering::Equal,
+                BinOp::Lt => ord == Ordering::Less,
+                BinOp::Le => ord != Ordering::Greater,
+                BinOp::Gt => ord == Ordering::Greater,
+                _ => ord != Ordering::Less
+            })
+        }
+        _ => {
+            let (a, b) = match (lhs.to_number(), rhs.to_number()) {
+                (Ok(a), Ok(b)) => (a, b),
+                (Err(e), _) | (_, Err(e)) => return Value::Error(e)
+            };
+            let res = match op {
+                BinOp::Add => a + b,
+                BinOp::Sub => a - b,
+                BinOp::Mul => a * b,
+                BinOp::Div => {
+                    if b == 0.0 {
+                        return Value::Error(CellError::DivZero);
+                    }
+                    a / b
+                }
+                _ => a.powf(b)
+            };
+            if res.is_finite() {
+                Value::Number(res)
+            } else {
+                Value::Error(CellError::Num)
+            }
+        }
+    }
+}
+
+/// Evaluate a function call
+fn evaluate_call(name: &str, args: &[Expr], lookup: &mut dyn CellLookup) -> Value {
+    // IF is evaluated lazily, so only the chosen branch is evaluated
+    if name == "IF" {
+        if args.len() < 2 || args.len() > 3 {
+            return Value::Error(CellError::Value);
+        }
+        return match evaluate(&args[0], lookup).to_bool() {
+            Ok(true) => evaluate(&args[1], lookup),
+            Ok(false) => args.get(2).map(|e| evaluate(e, lookup)).unwrap_or(Value::Bool(false)),
+            Err(e) => Value::Error(e)
+        };
+    }
+    let evaluated: Vec<Arg> = args.iter().map(|arg| match arg {
+        Expr::Range(start, end) => Arg::Range(evaluate_range(start, end, lookup)),
+        _ => Arg::Scalar(evaluate(arg, lookup))
+    }).collect();
+    match name {
+        "COUNT" => {
+            // Count the numbers, ignoring anything else (including errors)
+            let count = evaluated.iter().map(|arg| match arg {
+                Arg::Scalar(v) => usize::from(v.to_number().is_ok() && !matches!(v, Value::Empty)),
+                Arg::Range(values) => values.iter().filter(|v| matches!(v, Value::Number(_))).count()
+            }).sum::<usize>();
+            Value::Number(count as f64)
+        }
+        "SUM" | "AVERAGE" | "MIN" | "MAX" => {
+            match collect_numbers(&evaluated) {
+                Err(e) => Value::Error(e),
+                Ok(nums) => match name {
+                    "SUM" => Value::Number(nums.iter().sum()),
+                    "AVERAGE" => {
+                        if nums.is_empty() {
+                            Value::Error(CellError::DivZero)
+                        } else {
+                            Value::Number(nums.iter().sum::<f64>() / nums.len() as f64)
+                        }
+                    }
+                    "MIN" => Value::Number(nums.iter().copied().reduce(f64::min).unwrap_or(0.0)),
+                    _ => Value::Number(nums.iter().copied().reduce(f64::max).unwrap_or(0.0))
+                }
+            }
+        }
+        "CONCAT" => {
+            let mut res = String::new();
+            for arg in &evaluated {
+                let values = match arg {
+                    Arg::Scalar(v) => std::slice::from_ref(v),
+                    Arg::Range(vs) => vs.as_slice()
+                };
+                for v in values {
+                    if let Value::Error(e) = v {
+                        return Value::Error(*e);
+                    }
+                    res.push_str(&v.to_string());
+                }
+            }
+            Value::Text(res)
+        }
+        "ROUND" => {
+            let scalars: Vec<&Value> = evaluated.iter().filter_map(|arg| match arg {
+                Arg::Scalar(v) => Some(v),
+                Arg::Range(_) => None
+            }).collect();
+            if scalars.len() != evaluated.len() || scalars.is_empty() || scalars.len() > 2 {
+                return Value::Error(CellError::Value);
+            }
+            let n = match scalars[0].to_number() {
+                Ok(n) => n,
+                Err(e) => return Value::Error(e)
+            };
+            let digits = match scalars.get(1).map(|v| v.to_number()).unwrap_or(Ok(0.0)) {
+                Ok(d) => d.trunc() as i32,
+                Err(e) => return Value::Error(e)
+            };
+            let factor = 10f64.powi(digits);
+            let res = (n * factor).round() / factor;
+            if res.is_finite() {
+                Value::Number(res)
+            } else {
+                Value::Error(CellError::Num)
+            }
+        }
+        _ => Value::Error(CellError::Name)
+    }
+}
+
+/// Collect the numbers of function arguments (non-numeric range cells are skipped, like spreadsheets do)
+fn collect_numbers(args: &[Arg]) -> Result<Vec<f64>, CellError> {
+    let mut res: Vec<f64> = Vec::new();
+    for arg in args {
+        match arg {
+            Arg::Scalar(v) => res.push(v.to_number()?),
+            Arg::Range(values) => {
+                for v in values {
+                    match v {
+                        Value::Number(n) => res.push(*n),
+                        Value::Error(e) => return Err(*e),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// A cell or range of cells read by a formula
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Precedent {
+    Cell((usize, usize)),
+    Range((usize, usize), (usize, usize)) // Normalized so the first corner is the top left
+}
+
+impl Expr {
+    /// Collect the cells and ranges this expression reads
+    pub fn precedents(&self, res: &mut Vec<Precedent>) {
+        match self {
+            Expr::Ref(cellref) => res.push(Precedent::Cell(cellref.coords())),
+            Expr::Range(start, end) => res.push(Precedent::Range(
+                (start.row.min(end.row), start.col.min(end.col)),
+                (start.row.max(end.row), start.col.max(end.col))
+            )),
+            Expr::Neg(inner) => inner.precedents(res),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.precedents(res);
+                rhs.precedents(res);
+            }
+            Expr::Call(_, args) => {
+                for arg in args {
+                    arg.precedents(res);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A structural change to the sheet that moves cells (the index of the row or column inserted or deleted)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shift {
+    InsertRow(usize),
+    DeleteRow(usize),
+    InsertColumn(usize),
+    DeleteColumn(usize)
+}
+
+impl Shift {
+    /// Get the (index, absolute) of a reference on the axis this change moves
+    fn axis(&self, cellref: &CellRef) -> (usize, bool) {
+        match self {
+            Shift::InsertRow(_) | Shift::DeleteRow(_) => (cellref.row, cellref.row_absolute),
+            _ => (cellref.col, cellref.col_absolute)
+        }
+    }
+    /// Set the index of a reference on the axis this change moves
+    fn set_axis(&self, cellref: &mut CellRef, index: usize) {
+        match self {
+            Shift::InsertRow(_) | Shift::DeleteRow(_) => cellref.row = index,
+            _ => cellref.col = index
+        }
+    }
+    /// Adjust a single reference, or return None if it was deleted
+    fn adjust_ref(&self, cellref: &CellRef) -> Option<CellRef> {
+        let (index, absolute) = self.axis(cellref);
+        let mut res = *cellref;
+        if absolute {
+            return Some(res);
+        }
+        match *self {
+            Shift::InsertRow(at) | Shift::InsertColumn(at) => {
+                if index >= at {
+                    self.set_axis(&mut res, index + 1);
+                }
+            }
+            Shift::DeleteRow(at) | Shift::DeleteColumn(at) => {
+                if index == at {
+                    return None;
+                }
+                if index > at {
+                    self.set_axis(&mut res, index - 1);
+                }
+            }
+        }
+        Some(res)
+    }
+    /// Adjust a range, shrinking it if some of it was deleted, or return None if all of it was deleted
+    fn adjust_range(&self, start: &CellRef, end: &CellRef) -> Option<(CellRef, CellRef)> {
+        let (mut first, mut last) = (*start, *end);
+        let (firstindex, firstabsolute) = self.axis(&first);
+        let (lastindex, lastabsolute) = self.axis(&last);
+        // Which endpoint is the lower one on this axis
+        let (lo, lo_absolute, hi, hi_absolute, first_is_lo) = if firstindex <= lastindex {
+            (firstindex, firstabsolute, lastindex, lastabsolute, true)
+        } else {
+            (lastindex, lastabsolute, firstindex, firstabsolute, false)
+        };
+        let (newlo, newhi) = match *self {
+            Shift::InsertRow(at) | Shift::InsertColumn(at) => (
+                if !lo_absolute && lo >= at { lo + 1 } else { lo },
+                if !hi_absolute && hi >= at { hi + 1 } else { hi }
+            ),
+            Shift::DeleteRow(at) | Shift::DeleteColumn(at) => {
+                if lo == at && hi == at && !lo_absolute && !hi_absolute {
+                    return None;
+                }
+                let newlo = if !lo_absolute && lo > at { lo - 1 } else { lo };
+                let newhi = if !hi_absolute && hi >= at && hi > 0 && (hi > at || hi > lo) { hi - 1 } else { hi };
+                (newlo, newhi)
+            }
+        };
+        if first_is_lo {
+            self.set_axis(&mut first, newlo);
+            self.set_axis(&mut last, newhi);
+        } else {
+            self.set_axis(&mut first, newhi);
+            self.set_axis(&mut last, newlo);
+        }
+        Some((first, last))
+    }
+}
+
+/// Rewrite the references of a formula after a structural change, or return None if nothing changed
+/// Relative references move with their cells, references to deleted cells become #REF!, and absolute
+/// references (ex. `$A$1`) are left untouched
+pub fn adjust_references(text: &str, shift: Shift) -> Option<String> {
+    rewrite_references(text, |cellref| shift.adjust_ref(cellref), |start, end| shift.adjust_range(start, end))
+}
+
+/// Move the relative references of a formula by a (row, col) offset, as when copying it to another cell,
+/// or return None if nothing changed (references moved off the sheet become #REF!)
+pub fn translate_references(text: &str, offset: (isize, isize)) -> Option<String> {
+    let translate = |cellref: &CellRef| -> Option<CellRef> {
+        let mut res = *cellref;
+        if !res.row_absolute {
+            res.row = res.row.checked_add_signed(offset.0)?;
+        }
+        if !res.col_absolute {
+            res.col = res.col.checked_add_signed(offset.1)?;
+        }
+        Some(res)
+    };
+    rewrite_references(text, translate, |start, end| Some((translate(start)?, translate(end)?)))
+}
+
+/// Rewrite each reference and range of a formula, keeping the rest of its text as is
+/// (a reference or range rewritten to None becomes #REF!), or return None if nothing changed
+fn rewrite_references(
+    text: &str,
+    rewrite_ref: impl Fn(&CellRef) -> Option<CellRef>,
+    rewrite_range: impl Fn(&CellRef, &CellRef) -> Option<(CellRef, CellRef)>
+) -> Option<String> {
+    let body = text.strip_prefix('=')?;
+    let tokens = tokenize(body).ok()?;
+    let mut res = String::from("=");
+    let mut copied_to = 0; // Byte offset in body up to which the text has been copied
+    let mut changed = false;
+    let mut i = 0;
+    while i < tokens.len() {
+        let TokenKind::Ref(start) = tokens[i].kind else {
+            i += 1;
+            continue;
+        };
+        // A reference, or a range of two references
+        let range_end = match (tokens.get(i + 1).map(|t| &t.kind), tokens.get(i + 2).map(|t| &t.kind)) {
+            (Some(TokenKind::Colon), Some(TokenKind::Ref(end))) => Some(*end),
+            _ => None
+        };
+        let last = if range_end.is_some() { i + 2 } else { i };
+        let replacement = match range_end {
+            Some(end) => match rewrite_range(&start, &end) {
+                Some((newstart, newend)) if (newstart, newend) != (start, end) => Some(format!("{}:{}", newstart, newend)),
+                Some(_) => None,
+                None => Some(CellError::Ref.code().to_string())
+            },
+            None => match rewrite_ref(&start) {
+                Some(newref) if newref != start => Some(newref.to_string()),
+                Some(_) => None,
+                None => Some(CellError::Ref.code().to_string())
+            }
+        };
+        if let Some(replacement) = replacement {
+            res.push_str(&body[copied_to..tokens[i].start]);
+            res.push_str(&replacement);
+            copied_to = tokens[last].end;
+            changed = true;
+        }
+        i = last + 1;
+    }
+    if !changed {
+        return None;
+    }
+    res.push_str(&body[copied_to..]);
+    Some(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sheet of literal cells to evaluate formulas against
+    struct Cells(Vec<Vec<&'static str>>);
+
+    impl CellLookup for Cells {
+        fn value(&mut self, coords: (usize, usize)) -> Option<Value> {
+            self.0.get(coords.0)?.get(coords.1).map(|text| Value::from_literal(text))
+        }
+        fn bounds(&self) -> (usize, usize) {
+            (self.0.len(), self.0.first().map_or(0, |line| line.len()))
+        }
+    }
+
+    fn eval(text: &str) -> Value {
+        let mut cells = Cells(vec![vec!["1", "2"], vec!["3", "text"]]);
+        match parse(text) {
+            Ok(expr) => evaluate(&expr, &mut cells),
+            Err(e) => Value::Error(e)
+        }
+    }
+
+    #[test]
+    fn follows_precedence() {
+        assert_eq!(eval("=1+2*3"), Value::Number(7.0));
+        assert_eq!(eval("=(1+2)*3"), Value::Number(9.0));
+        assert_eq!(eval("=-2^2"), Value::Number(-4.0));
+        assert_eq!(eval("=(-2)^2"), Value::Number(4.0));
+        assert_eq!(eval("=2^3^2"), Value::Number(512.0));
+        assert_eq!(eval("=10-4-3"), Value::Number(3.0));
+        assert_eq!(eval("=1+1=2"), Value::Bool(true));
+        assert_eq!(eval("=1+2&\"x\""), Value::Text("3x".to_string()));
+    }
+
+    #[test]
+    fn reads_references() {
+        assert_eq!(eval("=A1&B2"), Value::Text("1text".to_string()));
+        assert_eq!(eval("=A1+(1, 0)"), Value::Number(4.0));
+        assert_eq!(eval("=SUM(($0, $0):B2)"), Value::Number(6.0));
+        assert_eq!(eval("=SUM((1, 0))"), Value::Number(3.0));
+        assert_eq!(eval("=(1,1)"), Value::Text("text".to_string()));
+        assert_eq!(eval("=SUM((1, 0)*2, (0, 1))"), Value::Number(8.0));
+        assert_eq!(eval("=(1, 0+1)"), Value::Error(CellError::Parse));
+        assert_eq!(eval("=(1)"), Value::Number(1.0));
+        assert_eq!(eval("=SUM((1), 2)"), Value::Number(3.0));
+    }
+
+    #[test]
+    fn produces_errors() {
+        assert_eq!(eval("=1/0"), Value::Error(CellError::DivZero));
+        assert_eq!(eval("=1/(A1-1)"), Value::Error(CellError::DivZero));
+        assert_eq!(eval("=C1"), Value::Error(CellError::Ref));
+        assert_eq!(eval("=(5, 0)+1"), Value::Error(CellError::Ref));
+        assert_eq!(eval("=B2*2"), Value::Error(CellError::Value));
+        assert_eq!(eval("=NOPE(1)"), Value::Error(CellError::Name));
+        assert_eq!(eval("=1+"), Value::Error(CellError::Parse));
+        assert_eq!(eval("=#CIRC!+1"), Value::Error(CellError::Circular));
+    }
+
+    #[test]
+    fn marks_circular_references() {
+        let mut sheet = crate::sheet::Sheet::new();
+        sheet.load_vector(&[
+            vec!["=B1".to_string(), "=A1".to_string(), "=A1+1".to_string()],
+            vec!["=A2".to_string(), "1".to_string(), "=B2*2".to_string()]
+        ]);
+        assert_eq!(sheet.display_value((0, 0)).unwrap(), "#CIRC!");
+        assert_eq!(sheet.display_value((0, 1)).unwrap(), "#CIRC!");
+        assert_eq!(sheet.display_value((1, 0)).unwrap(), "#CIRC!");
+        assert_eq!(sheet.display_value((1, 2)).unwrap(), "2");
+    }
+
+    #[test]
+    fn adjusts_references() {
+        assert_eq!(adjust_references("=A1+A3", Shift::InsertRow(1)).as_deref(), Some("=A1+A4"));
+        assert_eq!(adjust_references("=A1", Shift::InsertRow(1)), None);
+        assert_eq!(adjust_references("=$A$3+A3", Shift::InsertRow(0)).as_deref(), Some("=$A$3+A4"));
+        assert_eq!(adjust_references("=A2*2", Shift::DeleteRow(1)).as_deref(), Some("=#REF!*2"));
+        assert_eq!(adjust_references("=SUM(A1:A5)", Shift::DeleteRow(2)).as_deref(), Some("=SUM(A1:A4)"));
+        assert_eq!(adjust_references("=SUM(B1:B5)", Shift::DeleteColumn(1)).as_deref(), Some("=SUM(#REF!)"));
+        assert_eq!(adjust_references("=SUM((2, 1), 1)", Shift::InsertColumn(0)).as_deref(), Some("=SUM((2, 2), 1)"));
+        assert_eq!(translate_references("=A1+$B$1", (2, 1)).as_deref(), Some("=B3+$B$1"));
+        assert_eq!(translate_references("=A1", (-1, 0)).as_deref(), Some("=#REF!"));
+    }
+}
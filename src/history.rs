@@ -0,0 +1,569 @@
+use std::{ cmp, mem, collections::BTreeMap, time::{ Duration, SystemTime } };
+use crate::csvformat;
+
+/// The first field of an undo file, identifying its format
+const UNDO_FILE_HEADER: &str = "sheatfish undo 1";
+
+/// A change to the sheet (applying one gives back the operations that undo it)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    SetCells(Vec<((usize, usize), String)>), // Set the text of cells, in order
+    InsertRow(usize, Vec<String>), // Insert a row with its cells
+    DeleteRow(usize),
+    InsertColumn(usize, Vec<String>, Option<usize>), // Insert a column with its cells (from the top) and display width
+    DeleteColumn(usize),
+    PermuteRows(usize, Vec<usize>), // Reorder the rows from a row, so the i-th of them becomes the row that was at order[i]
+    PermuteColumns(usize, Vec<usize>), // Reorder the columns from a column, so the i-th of them becomes the column that was at order[i]
+    Resize((usize, usize)) // Grow or shrink the sheet to a size (rows, columns)
+}
+
+impl Operation {
+    /// Get the fields of the operation as a line of an undo file (ex. `op, DeleteRow, 3`)
+    fn to_fields(&self) -> Vec<String> {
+        let mut res = vec!["op".to_string()];
+        match self {
+            Operation::SetCells(cells) => {
+                res.push("SetCells".to_string());
+                for ((row, col), text) in cells {
+                    res.extend([row.to_string(), col.to_string(), text.clone()]);
+                }
+            }
+            Operation::InsertRow(row, cells) => {
+                res.extend(["InsertRow".to_string(), row.to_string()]);
+                res.extend(cells.iter().cloned());
+            }
+            Operation::DeleteRow(row) => res.extend(["DeleteRow".to_string(), row.to_string()]),
+            Operation::InsertColumn(col, cells, width) => {
+                res.extend(["InsertColumn".to_string(), col.to_string(), fmt_optional(*width)]);
+                res.extend(cells.iter().cloned());
+            }
+            Operation::DeleteColumn(col) => res.extend(["DeleteColumn".to_string(), col.to_string()]),
+            Operation::PermuteRows(start, order) | Operation::PermuteColumns(start, order) => {
+                let name = if matches!(self, Operation::PermuteRows(..)) { "PermuteRows" } else { "PermuteColumns" };
+                res.extend([name.to_string(), start.to_string()]);
+                res.extend(order.iter().map(|i| i.to_string()));
+            }
+            Operation::Resize((rows, cols)) => res.extend(["Resize".to_string(), rows.to_string(), cols.to_string()])
+        }
+        res
+    }
+    /// Parse the operation from the fields of a line of an undo file (after `op`), or None if invalid
+    fn from_fields(fields: &[String]) -> Option<Operation> {
+        let (name, rest) = fields.split_first()?;
+        let number = |i: usize| rest.get(i)?.parse::<usize>().ok();
+        let numbers = |from: usize| rest.get(from..).unwrap_or_default().iter().map(|f| f.parse::<usize>().ok()).collect::<Option<Vec<usize>>>();
+        Some(match name.as_str() {
+            "SetCells" => {
+                let cells = rest.chunks(3).map(|chunk| match chunk {
+                    [row, col, text] => Some(((row.parse().ok()?, col.parse().ok()?), text.clone())),
+                    _ => None
+                });
+                Operation::SetCells(cells.collect::<Option<Vec<_>>>()?)
+            }
+            "InsertRow" => Operation::InsertRow(number(0)?, rest.get(1..)?.to_vec()),
+            "DeleteRow" => Operation::DeleteRow(number(0)?),
+            "InsertColumn" => Operation::InsertColumn(number(0)?, rest.get(2..)?.to_vec(), parse_optional(rest.get(1)?)?),
+            "DeleteColumn" => Operation::DeleteColumn(number(0)?),
+            "PermuteRows" => Operation::PermuteRows(number(0)?, numbers(1)?),
+            "PermuteColumns" => Operation::PermuteColumns(number(0)?, numbers(1)?),
+            "Resize" => Operation::Resize((number(0)?, number(1)?)),
+            _ => return None
+        })
+    }
+}
+
+/// The operations undoing (or redoing) a change, and the selected cell to go back to
+#[derive(Clone, Debug, Default)]
+pub struct Step {
+    pub ops: Vec<Operation>, // Applied from last to first
+    pub selected: Option<(usize, usize)>
+}
+
+/// A state of the sheet in the undo tree, after a change to its parent state
+#[derive(Clone, Debug)]
+struct Node {
+    parent: Option<u64>, // None for the base state
+    step: Step, // Undoes the change while it is applied (this state is the current one or before it), and redoes it otherwise
+    time: SystemTime, // When the change was made
+    redo: Option<u64> // The state to redo into from this one (the one last made or undone)
+}
+
+/// A state of the sheet, as listed by `undolist`
+pub struct StateInfo {
+    pub id: u64,
+    pub parent: Option<u64>, // None for the base state
+    pub time: SystemTime,
+    pub current: bool,
+    pub saved: bool,
+    pub leaf: bool // Whether it ends a branch
+}
+
+impl StateInfo {
+    /// Get how long ago the state was made (ex. `5m ago`)
+    pub fn age(&self) -> String {
+        let seconds = SystemTime::now().duration_since(self.time).unwrap_or_default().as_secs();
+        match seconds {
+            0..=59 => format!("{}s ago", seconds),
+            60..=3599 => format!("{}m ago", seconds / 60),
+            3600..=86399 => format!("{}h ago", seconds / 3600),
+            _ => format!("{}d ago", seconds / 86400)
+        }
+    }
+}
+
+/// How far to go back or forward through the history with `earlier` or `later`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Travel {
+    States(usize), // A number of states, in the order they were made
+    Seconds(u64) // An amount of time
+}
+
+impl Travel {
+    /// Parse a number of states (ex. `3`) or an amount of time in seconds, minutes, hours or days (ex. `10s`, `5m`, `2h`, `1d`)
+    pub fn parse(text: &str) -> Option<Travel> {
+        if let Ok(count) = text.parse::<usize>() {
+            return Some(Travel::States(count));
+        }
+        let unit = match text.chars().last()? {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return None
+        };
+        let amount = text[..text.len() - 1].parse::<u64>().ok()?;
+        Some(Travel::Seconds(amount.checked_mul(unit)?))
+    }
+}
+
+/// Stores the changes to a sheet as a tree of states, so changes undone and then replaced by new ones can still be gone back to
+/// States are numbered in the order they were made (the base state, before any kept change, has the lowest number)
+#[derive(Debug)]
+pub struct History {
+    nodes: BTreeMap<u64, Node>, // The states after each kept change
+    base: u64, // The state before the first kept change
+    basetime: SystemTime,
+    baseredo: Option<u64>, // The state to redo into from the base state
+    current: u64,
+    nextid: u64, // The number of the next state made
+    pending: Option<Step>, // Changes made since the last step ended
+    depth: usize, // How many transactions are open (the step only ends when none are)
+    saved: Option<u64> // The state of the sheet when it was last loaded or saved (None if it never was)
+}
+
+impl History {
+    /// Forget all states (the current state of the sheet becomes the base state, and the saved state if saved)
+    pub fn clear(&mut self, saved: bool) {
+        *self = History { saved: if saved { Some(0) } else { None }, ..History::default() };
+    }
+    /// Record the operations undoing a change, as part of the current step
+    /// (selected is the selected cell before the change)
+    pub fn record(&mut self, inverse: Vec<Operation>, selected: Option<(usize, usize)>) {
+        if inverse.is_empty() {
+            return;
+        }
+        let step = self.pending.get_or_insert_with(|| Step { ops: Vec::new(), selected });
+        step.ops.extend(inverse.into_iter().rev());
+    }
+    /// Start a transaction: changes are recorded as part of the current step until it is committed
+    pub fn begin(&mut self) {
+        self.depth += 1;
+    }
+    /// Commit a transaction, ending the current step if it was the outermost one (see end_step)
+    pub fn commit(&mut self, limit: usize) -> bool {
+        self.depth = self.depth.saturating_sub(1);
+        self.end_step(limit)
+    }
+    /// End the current step (unless in a transaction) as a new state after the current one, keeping at most limit states,
+    /// and return whether there was anything to end (undone changes are kept in their own branch)
+    pub fn end_step(&mut self, limit: usize) -> bool {
+        if self.depth > 0 {
+            return false;
+        }
+        let Some(step) = self.pending.take() else {
+            return false;
+        };
+        let id = self.nextid;
+        self.nextid += 1;
+        let parent = self.parent_of(self.current);
+        self.nodes.insert(id, Node { parent, step, time: SystemTime::now(), redo: None });
+        self.set_redo(parent, id);
+        self.current = id;
+        while self.nodes.len() > limit {
+            self.drop_oldest();
+        }
+        true
+    }
+    /// Get a state as the parent of the states after it (None for the base state)
+    fn parent_of(&self, id: u64) -> Option<u64> {
+        if id == self.base { None } else { Some(id) }
+    }
+    /// Set the state to redo into from a state (None for the base state)
+    fn set_redo(&mut self, parent: Option<u64>, id: u64) {
+        match parent.and_then(|p| self.nodes.get_mut(&p)) {
+            Some(node) => node.redo = Some(id),
+            None => self.baseredo = Some(id)
+        }
+    }
+    /// Forget the oldest change, so states can no longer be undone past it
+    fn drop_oldest(&mut self) {
+        let Some((&oldest, _)) = self.nodes.first_key_value() else {
+            return;
+        };
+        if self.path_from_base(self.current).contains(&oldest) {
+            // The oldest state becomes the base state, and the other branches from the old base state are lost
+            let Some(node) = self.nodes.remove(&oldest) else {
+                return;
+            };
+            self.base = oldest;
+            self.basetime = node.time;
+            self.baseredo = node.redo;
+            let others: Vec<u64> = self.nodes.iter().filter(|(_, n)| n.parent.is_none()).map(|(&id, _)| id).collect();
+            for id in others {
+                self.remove_branch(id);
+            }
+            for node in self.nodes.values_mut() {
+                if node.parent == Some(oldest) {
+                    node.parent = None;
+                }
+            }
+        } else {
+            self.remove_branch(oldest);
+        }
+    }
+    /// Remove a state and every state after it
+    fn remove_branch(&mut self, id: u64) {
+        let children: Vec<u64> = self.nodes.iter().filter(|(_, n)| n.parent == Some(id)).map(|(&child, _)| child).collect();
+        for child in children {
+            self.remove_branch(child);
+        }
+        self.nodes.remove(&id);
+        for node in self.nodes.values_mut() {
+            if node.redo == Some(id) {
+                node.redo = None;
+            }
+        }
+        if self.baseredo == Some(id) {
+            self.baseredo = None;
+        }
+    }
+    /// Get the states from after the base state to a state, inclusive
+    fn path_from_base(&self, id: u64) -> Vec<u64> {
+        let mut res = Vec::new();
+        let mut at = self.parent_of(id);
+        while let Some(node) = at {
+            res.push(node);
+            at = self.nodes.get(&node).and_then(|n| n.parent);
+        }
+        res.reverse();
+        res
+    }
+    /// Get the states whose changes to undo (in order), then redo (in order), to go from the current state to another
+    /// (None if there is no such state)
+    pub fn path_to(&self, target: u64) -> Option<(Vec<u64>, Vec<u64>)> {
+        if target != self.base && !self.nodes.contains_key(&target) {
+            return None;
+        }
+        let from = self.path_from_base(self.current);
+        let to = self.path_from_base(target);
+        let common = from.iter().zip(to.iter()).take_while(|(a, b)| a == b).count();
+        let undo: Vec<u64> = from[common..].iter().rev().copied().collect();
+        Some((undo, to[common..].to_vec()))
+    }
+    /// Take the step undoing (or redoing) the change made by a state
+    pub fn take_step(&mut self, id: u64) -> Step {
+        self.nodes.get_mut(&id).map(|node| mem::take(&mut node.step)).unwrap_or_default()
+    }
+    /// Store the step reversing the change of a state, after it was undone (or redone) with the step from take_step
+    /// The current state moves to before (or to) the state, and redoing goes back into it
+    pub fn put_step(&mut self, id: u64, step: Step, undone: bool) {
+        let Some(node) = self.nodes.get_mut(&id) else {
+            return;
+        };
+        node.step = step;
+        let parent = node.parent;
+        self.current = if undone { parent.unwrap_or(self.base) } else { id };
+        self.set_redo(parent, id);
+    }
+    /// Get the state before the current one (None if at the base state)
+    pub fn undo_target(&self) -> Option<u64> {
+        self.nodes.get(&self.current).map(|node| node.parent.unwrap_or(self.base))
+    }
+    /// Get the state to redo into from the current one (None if there is none)
+    pub fn redo_target(&self) -> Option<u64> {
+        match self.nodes.get(&self.current) {
+            Some(node) => node.redo,
+            None => self.baseredo
+        }
+    }
+    /// Get the state made count states before (if negative) or after the current one, in the order states were made
+    /// (stopping at the oldest and newest states)
+    pub fn chronological_target(&self, count: isize) -> u64 {
+        let ids: Vec<u64> = std::iter::once(self.base).chain(self.nodes.keys().copied()).collect();
+        let index = ids.iter().position(|&id| id == self.current).unwrap_or(0);
+        ids[cmp::min(index.saturating_add_signed(count), ids.len() - 1)]
+    }
+    /// Get the newest state made at most a number of seconds after (or, if negative, before) the current one was made
+    pub fn time_target(&self, seconds: i64) -> u64 {
+        let now = self.time_of(self.current);
+        let offset = Duration::from_secs(seconds.unsigned_abs());
+        let target = if seconds < 0 {
+            now.checked_sub(offset).unwrap_or(SystemTime::UNIX_EPOCH)
+        } else {
+            now + offset
+        };
+        self.nodes.iter().filter(|(_, n)| n.time <= target).map(|(&id, _)| id).next_back().unwrap_or(self.base)
+    }
+    /// Get when a state was made
+    fn time_of(&self, id: u64) -> SystemTime {
+        self.nodes.get(&id).map(|node| node.time).unwrap_or(self.basetime)
+    }
+    /// Get every state, oldest first
+    pub fn states(&self) -> Vec<StateInfo> {
+        let info = |id: u64, parent: Option<u64>, time: SystemTime| StateInfo {
+            id,
+            parent,
+            time,
+            current: id == self.current,
+            saved: self.saved == Some(id),
+            leaf: !self.nodes.values().any(|n| n.parent == self.parent_of(id))
+        };
+        let mut res = vec![info(self.base, None, self.basetime)];
+        res.extend(self.nodes.iter().map(|(&id, node)| info(id, Some(node.parent.unwrap_or(self.base)), node.time)));
+        res
+    }
+    /// Write the states as the text of an undo file, for the saved file with a content hash (see content_hash)
+    pub fn to_text(&self, hash: u64) -> String {
+        let mut rows: Vec<Vec<String>> = vec![vec![
+            UNDO_FILE_HEADER.to_string(),
+            hash.to_string(),
+            self.base.to_string(),
+            fmt_time(self.basetime),
+            fmt_optional(self.baseredo),
+            self.current.to_string(),
+            self.nextid.to_string(),
+            fmt_optional(self.saved)
+        ]];
+        for (id, node) in &self.nodes {
+            let (row, col) = (node.step.selected.map(|s| s.0), node.step.selected.map(|s| s.1));
+            rows.push(vec![
+                "state".to_string(),
+                id.to_string(),
+                fmt_optional(node.parent),
+                fmt_time(node.time),
+                fmt_optional(node.redo),
+                fmt_optional(row),
+                fmt_optional(col)
+            ]);
+            rows.extend(node.step.ops.iter().map(|op| op.to_fields()));
+        }
+        csvformat::generate(&rows, ',')
+    }
+    /// Read the states from the text of an undo file, if it is for a file with a content hash (None otherwise, or if invalid)
+    pub fn from_text(text: &str, hash: u64) -> Option<History> {
+        let rows = csvformat::parse(text, ',').ok()?;
+        let (header, lines) = rows.split_first()?;
+        let [name, filehash, base, basetime, baseredo, current, nextid, saved] = header.as_slice() else {
+            return None;
+        };
+        if name != UNDO_FILE_HEADER || filehash.parse::<u64>().ok()? != hash {
+            return None;
+        }
+        let mut res = History {
+            base: base.parse().ok()?,
+            basetime: parse_time(basetime)?,
+            baseredo: parse_optional(baseredo)?,
+            current: current.parse().ok()?,
+            nextid: nextid.parse().ok()?,
+            saved: parse_optional(saved)?,
+            ..History::default()
+        };
+        let mut last: Option<u64> = None;
+        for line in lines {
+            match line.split_first()? {
+                (kind, [id, parent, time, redo, row, col]) if kind == "state" => {
+                    let id: u64 = id.parse().ok()?;
+                    let selected = parse_optional::<usize>(row)?.zip(parse_optional::<usize>(col)?);
+                    let node = Node {
+                        parent: parse_optional(parent)?,
+                        step: Step { ops: Vec::new(), selected },
+                        time: parse_time(time)?,
+                        redo: parse_optional(redo)?
+                    };
+                    res.nodes.insert(id, node);
+                    last = Some(id);
+                }
+                (kind, fields) if kind == "op" => {
+                    let op = Operation::from_fields(fields)?;
+                    res.nodes.get_mut(&last?)?.step.ops.push(op);
+                }
+                _ => return None
+            }
+        }
+        // The current state must be one of the states (it is the state of the file)
+        if res.current != res.base && !res.nodes.contains_key(&res.current) {
+            return None;
+        }
+        Some(res)
+    }
+    /// Mark the current state of the sheet as saved
+    pub fn mark_saved(&mut self) {
+        self.saved = Some(self.current);
+    }
+    /// Whether the sheet has changed since it was last saved (or loaded)
+    pub fn is_unsaved(&self) -> bool {
+        self.pending.is_some() || self.saved != Some(self.current)
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            nodes: BTreeMap::new(),
+            base: 0,
+            basetime: SystemTime::now(),
+            baseredo: None,
+            current: 0,
+            nextid: 1,
+            pending: None,
+            depth: 0,
+            saved: None
+        }
+    }
+}
+
+/// Get the hash (64-bit FNV-1a) of the contents of a file, to tell whether an undo file is for it
+pub fn content_hash(text: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in text.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Format an optional number for an undo file (empty if None)
+fn fmt_optional<T: ToString>(value: Option<T>) -> String {
+    value.map(|v| v.to_string()).unwrap_or_default()
+}
+
+/// Parse an optional number of an undo file (Some(None) if empty, None if invalid)
+fn parse_optional<T: std::str::FromStr>(text: &str) -> Option<Option<T>> {
+    if text.is_empty() {
+        Some(None)
+    } else {
+        text.parse().ok().map(Some)
+    }
+}
+
+/// Format a time for an undo file (seconds since the Unix epoch)
+fn fmt_time(time: SystemTime) -> String {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs().to_string()
+}
+
+/// Parse a time of an undo file (seconds since the Unix epoch)
+fn parse_time(text: &str) -> Option<SystemTime> {
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(text.parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Make a change setting a cell, as a new state
+    fn change(history: &mut History, text: &str) -> u64 {
+        history.record(vec![Operation::SetCells(vec![((0, 0), text.to_string())])], Some((0, 0)));
+        assert!(history.end_step(100));
+        history.current
+    }
+
+    /// Undo or redo the change of a state
+    fn travel(history: &mut History, id: u64, undone: bool) {
+        let step = history.take_step(id);
+        history.put_step(id, step, undone);
+    }
+
+    /// A history of states 1 -> 2, then 1 -> 3 after undoing 2, made 10 seconds apart
+    fn branching() -> History {
+        let mut history = History::default();
+        change(&mut history, "a");
+        change(&mut history, "b");
+        travel(&mut history, 2, true);
+        change(&mut history, "c");
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        history.basetime = start;
+        for (i, node) in history.nodes.values_mut().enumerate() {
+            node.time = start + Duration::from_secs(10 * (i as u64 + 1));
+        }
+        history
+    }
+
+    #[test]
+    fn undoes_and_redoes_into_the_last_branch() {
+        let mut history = branching();
+        assert_eq!(history.current, 3);
+        assert_eq!(history.undo_target(), Some(1));
+        travel(&mut history, 3, true);
+        assert_eq!(history.redo_target(), Some(3));
+        assert_eq!(history.path_to(2), Some((vec![], vec![2])));
+        assert_eq!(history.path_to(9), None);
+        travel(&mut history, 1, true);
+        assert_eq!(history.undo_target(), None);
+        assert_eq!(history.redo_target(), Some(1));
+    }
+
+    #[test]
+    fn goes_earlier_and_later_across_branches() {
+        let history = branching();
+        assert_eq!(history.chronological_target(-1), 2);
+        assert_eq!(history.chronological_target(-2), 1);
+        assert_eq!(history.chronological_target(-10), 0);
+        assert_eq!(history.chronological_target(5), 3);
+        assert_eq!(history.path_to(history.chronological_target(-1)), Some((vec![3], vec![2])));
+        assert_eq!(history.time_target(-10), 2);
+        assert_eq!(history.time_target(-15), 1);
+        assert_eq!(history.time_target(-100), 0);
+        assert_eq!(history.time_target(60), 3);
+    }
+
+    #[test]
+    fn keeps_at_most_the_limit() {
+        let mut history = History::default();
+        for text in ["a", "b", "c", "d"] {
+            history.record(vec![Operation::SetCells(vec![((0, 0), text.to_string())])], None);
+            history.end_step(2);
+        }
+        assert_eq!(history.states().iter().map(|s| s.id).collect::<Vec<_>>(), [2, 3, 4]);
+        assert_eq!(history.chronological_target(-10), 2);
+    }
+
+    #[test]
+    fn round_trips_undo_files() {
+        let mut history = branching();
+        history.record(vec![
+            Operation::InsertRow(1, vec!["x,\"y\"".to_string(), "two\nlines".to_string()]),
+            Operation::InsertColumn(0, vec![String::new()], Some(12)),
+            Operation::PermuteRows(2, vec![3, 2]),
+            Operation::Resize((4, 5))
+        ], None);
+        history.end_step(100);
+        history.mark_saved();
+        let text = history.to_text(42);
+        let loaded = History::from_text(&text, 42).expect("undo file should load");
+        assert_eq!(loaded.to_text(42), text);
+        assert!(!loaded.is_unsaved());
+        assert_eq!(loaded.nodes[&4].step.ops, history.nodes[&4].step.ops);
+        assert!(History::from_text(&text, 43).is_none());
+        assert!(History::from_text(&text.replace("state", "stat"), 42).is_none());
+        assert!(History::from_text("", 42).is_none());
+    }
+
+    #[test]
+    fn parses_travel() {
+        assert_eq!(Travel::parse("3"), Some(Travel::States(3)));
+        assert_eq!(Travel::parse("10m"), Some(Travel::Seconds(600)));
+        assert_eq!(Travel::parse("2d"), Some(Travel::Seconds(172800)));
+        assert_eq!(Travel::parse("m"), None);
+        assert_eq!(Travel::parse("5w"), None);
+    }
+}
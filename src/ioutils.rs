@@ -1,6 +1,7 @@
 use std::{ io, io::Write };
+use crate::textwidth;
 use crossterm::{
-    execute, queue, cursor, terminal, style::{self, Stylize, StyledContent}
+    execute, queue, cursor, event, terminal, style::{self, Stylize, StyledContent}
 };
 
 /// Read inputted character
@@ -17,23 +18,61 @@ pub fn _read_char() -> char {
     '!'
 }
 
-/// Wait for and read an inputted key code from crossterm
-pub fn read_key() -> crossterm::event::KeyCode {
+/// An input read from the terminal
+pub enum Input {
+    Key(crossterm::event::KeyEvent), // A pressed key (code and modifiers)
+    Paste(String), // Text pasted all at once (with bracketed paste)
+    Resize // The terminal was resized
+}
+
+/// Wait for and read an input (a key, pasted text or a resize) from crossterm
+pub fn read_input() -> Input {
     // Read the event
     match crossterm::event::read() {
-        // Only return a code on the Ok key event
-        Ok(crossterm::event::Event::Key(k)) => {
-            // Only return if the key is pressed
-            if k.kind == crossterm::event::KeyEventKind::Press {
-                k.code
-            } else {
-                crossterm::event::KeyCode::Null
-            }
+        // Only return a code on the Ok key event, and only if the key is pressed
+        Ok(crossterm::event::Event::Key(k)) if k.kind == crossterm::event::KeyEventKind::Press => {
+            Input::Key(k)
+        }
+        Ok(crossterm::event::Event::Paste(text)) => {
+            Input::Paste(text)
+        }
+        Ok(crossterm::event::Event::Resize(_, _)) => {
+            Input::Resize
         }
         _ => {
-            crossterm::event::KeyCode::Null
+            Input::Key(crossterm::event::KeyEvent::new(crossterm::event::KeyCode::Null, crossterm::event::KeyModifiers::NONE))
+        }
+    }
+}
+
+/// Encode bytes as base64 (standard alphabet, with padding)
+fn encode_base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut res = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                res.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                res.push('=');
+            }
         }
     }
+    res
+}
+
+/// Copy text to the system clipboard with the OSC 52 escape sequence
+/// (the terminal does the copying, so this also works over SSH; inside tmux, the sequence is passed through to the outer terminal)
+pub fn copy_to_system_clipboard(text: &str, stdout: &mut io::Stdout) -> io::Result<()> {
+    let sequence = format!("\x1b]52;c;{}\x07", encode_base64(text.as_bytes()));
+    if std::env::var_os("TMUX").is_some() {
+        write!(stdout, "\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"))?;
+    } else {
+        write!(stdout, "{}", sequence)?;
+    }
+    stdout.flush()?;
+    io::Result::Ok(())
 }
 
 /// Print plain text at a coordinate
@@ -48,7 +87,7 @@ pub fn printstyl(x: u16, y: u16, contents: StyledContent<String>, stdout: &mut i
     io::Result::Ok(())
 }
 
-/// Purge the screen
+// Purge the screen
 // TODO: impl this (to separate Purge from Clear, for perf.)
 
 /// Clear the screen
@@ -59,10 +98,13 @@ pub fn clear(stdout: &mut io::Stdout) -> io::Result<()> {
 }
 
 /// Switch between raw and normal mode
+/// (bracketed paste is only enabled in raw mode, where pasted text is read as a whole instead of key by key)
 pub fn set_raw_mode(use_raw_mode: bool) -> io::Result<()> {
     if use_raw_mode {
         terminal::enable_raw_mode()?;
+        execute!(io::stdout(), event::EnableBracketedPaste)?;
     } else {
+        execute!(io::stdout(), event::DisableBracketedPaste)?;
         terminal::disable_raw_mode()?;
     }
     io::Result::Ok(())
@@ -75,28 +117,26 @@ pub fn flush(stdout: &mut io::Stdout) -> io::Result<()> {
 }
 
 const CLEAR_WIDTH: u16 = 83;
+/// Where the value of the selected cell is printed, after its coordinates
+pub const LEFT_INPUT_WORD_BOUND: u16 = 15;
+/// How many terminal columns the value of the selected cell can take
+pub const INPUT_WORD_WIDTH: u16 = CLEAR_WIDTH - LEFT_INPUT_WORD_BOUND;
 
 /// Print an input word (the value of the cell currently being edited/viewed)
+/// If too wide, the end (where typing happens) is kept
 pub fn print_input_word(vstart: u16, stdout: &mut io::Stdout, inputword: &str) -> io::Result<()> {
-    const LEFT_INPUT_WORD_BOUND: u16 = 15;
-    let clearing_string: &str = &(0..(CLEAR_WIDTH - LEFT_INPUT_WORD_BOUND)).map(|_| " ").collect::<String>();
+    let clearing_string: &str = &(0..INPUT_WORD_WIDTH).map(|_| " ").collect::<String>();
     printat(LEFT_INPUT_WORD_BOUND, vstart, clearing_string, stdout)?;
-    printat(LEFT_INPUT_WORD_BOUND, vstart, inputword, stdout)?;
+    printat(LEFT_INPUT_WORD_BOUND, vstart, &textwidth::fit_end(inputword, INPUT_WORD_WIDTH.into()), stdout)?;
     flush(stdout)?;
     io::Result::Ok(())
 }
 
-/// Display the command prompt
-pub fn print_command_prompt(vstart: u16, stdout: &mut io::Stdout) -> io::Result<()> {
-    let clearing_string: &str = &(0..CLEAR_WIDTH).map(|_| " ").collect::<String>();
-    printat(0, vstart + 1, clearing_string, stdout)?;
-    printat(0, vstart + 1, "Enter a command (see README.md for commands): ", stdout)?;
-    flush(stdout)?;
-    io::Result::Ok(())
-}
+/// Shown before the command being typed
+pub const COMMAND_PROMPT: &str = "Enter a command (see README.md for commands): ";
 
 /// Clear the area below the command prompt
-fn clear_status_region(vstart: u16, stdout: &mut io::Stdout) -> io::Result<()> {
+pub fn clear_status_region(vstart: u16, stdout: &mut io::Stdout) -> io::Result<()> {
     const CLEAR_HEIGHT: u16 = 8;
     let clearing_string: &str = &(0..CLEAR_WIDTH).map(|_| " ").collect::<String>();
     for i in (1..CLEAR_HEIGHT).rev() {
@@ -0,0 +1,587 @@
+use std::{ fs, io, path::PathBuf };
+use crossterm::{
+    queue, cursor, terminal, event::{ KeyCode, KeyEvent, KeyModifiers }, style::Stylize
+};
+use homedir::my_home;
+use unicode_segmentation::UnicodeSegmentation;
+use crate::ioutils::{ self, Input, printat, printstyl, flush };
+use crate::textwidth;
+use crate::completion::{ self, Completions };
+
+/// Max number of commands kept in the command history
+const HISTORY_SIZE: usize = 1000;
+/// Max number of lines of completion candidates listed below the prompt
+const LISTING_HEIGHT: usize = 6;
+
+/// Stores the previously entered commands, oldest first
+pub struct CommandHistory {
+    entries: Vec<String>,
+    savepath: PathBuf
+}
+
+impl CommandHistory {
+    /// Create a command history, loaded from its file in the home directory if it exists
+    pub fn new() -> CommandHistory {
+        let mut savepath = match my_home() {
+            Ok(Some(homepath)) => homepath,
+            _ => PathBuf::from("./")
+        };
+        savepath.push(".sheatfish_history");
+        let entries = fs::read_to_string(&savepath)
+            .unwrap_or_default()
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(|line| line.to_string())
+            .collect();
+        CommandHistory { entries, savepath }
+    }
+
+    /// Add an entered command (unless empty or the same as the last one) and save to the file
+    pub fn push(&mut self, line: &str) {
+        if line.trim().is_empty() || self.entries.last().is_some_and(|last| last == line) {
+            return;
+        }
+        self.entries.push(line.to_string());
+        if self.entries.len() > HISTORY_SIZE {
+            self.entries.drain(..self.entries.len() - HISTORY_SIZE);
+        }
+        let mut res = self.entries.join("\n");
+        res.push('\n');
+        let _ = fs::write(&self.savepath, res);
+    }
+
+    /// Find the newest command before an index containing some text
+    fn find_before(&self, before: usize, query: &str) -> Option<usize> {
+        self.entries[..before.min(self.entries.len())].iter().rposition(|entry| entry.contains(query))
+    }
+}
+
+impl Default for CommandHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How the typing of a line ended
+#[derive(Debug, PartialEq)]
+pub enum LineEnd {
+    Submit(String), // Enter was pressed
+    Cancel // Esc (or Ctrl-C) was pressed
+}
+
+/// A reverse search (Ctrl-R) through the command history
+struct ReverseSearch {
+    query: String,
+    found: Option<usize>, // The history entry shown
+    failed: bool, // Whether the query matches nothing (older than the entry shown)
+    original: (String, usize) // The line and cursor from before the search, restored if cancelled
+}
+
+/// Tab completion of a word, cycling through its candidates
+struct Completion {
+    start: usize, // Byte index of the start of the word
+    candidates: Vec<String>,
+    index: Option<usize> // The candidate in the line (None if only their common start is)
+}
+
+/// A line of text being typed, with a cursor
+pub struct LineEditor {
+    text: String,
+    cursor: usize, // Byte index into the text, always at the start of a grapheme cluster (or the end)
+    scroll: usize, // Terminal columns of the text cut off at the left, when it is too wide to show
+    recall: Option<usize>, // The history entry being shown with up/down (None if typing a new line)
+    draft: String, // The new line, kept while going through the history
+    search: Option<ReverseSearch>,
+    completion: Option<Completion>,
+    relist: bool // Whether the completion candidates changed (or stopped) since they were listed
+}
+
+impl LineEditor {
+    /// Create an empty line
+    pub fn new() -> LineEditor {
+        LineEditor {
+            text: String::new(),
+            cursor: 0,
+            scroll: 0,
+            recall: None,
+            draft: String::new(),
+            search: None,
+            completion: None,
+            relist: false
+        }
+    }
+
+    /// Get the byte indices of the starts of the grapheme clusters of the text, and its end
+    fn boundaries(&self) -> Vec<usize> {
+        self.text.grapheme_indices(true).map(|(i, _)| i).chain([self.text.len()]).collect()
+    }
+
+    /// Get the boundary before the cursor (or the cursor, if at the start)
+    fn prev_boundary(&self) -> usize {
+        self.boundaries().into_iter().rev().find(|&i| i < self.cursor).unwrap_or(self.cursor)
+    }
+
+    /// Get the boundary after the cursor (or the cursor, if at the end)
+    fn next_boundary(&self) -> usize {
+        self.boundaries().into_iter().find(|&i| i > self.cursor).unwrap_or(self.cursor)
+    }
+
+    /// Get the start of the word before the cursor (skipping whitespace first)
+    fn prev_word_start(&self) -> usize {
+        let trimmed = self.text[..self.cursor].trim_end();
+        trimmed.char_indices().rev().find(|(_, c)| c.is_whitespace()).map_or(0, |(i, c)| i + c.len_utf8())
+    }
+
+    /// Get the end of the word after the cursor (skipping whitespace first)
+    fn next_word_end(&self) -> usize {
+        let after = &self.text[self.cursor..];
+        let skipped = after.len() - after.trim_start().len();
+        after[skipped..].find(char::is_whitespace).map(|i| self.cursor + skipped + i).unwrap_or(self.text.len())
+    }
+
+    /// Insert text at the cursor (line breaks and other control characters become spaces)
+    pub fn insert(&mut self, text: &str) {
+        let text: String = text.replace("\r\n", "\n").chars().map(|c| if c.is_control() { ' ' } else { c }).collect();
+        self.text.insert_str(self.cursor, &text);
+        self.cursor += text.len();
+    }
+
+    /// Delete the text between the cursor and another byte index
+    fn delete_to(&mut self, other: usize) {
+        let (start, end) = (self.cursor.min(other), self.cursor.max(other));
+        self.text.replace_range(start..end, "");
+        self.cursor = start;
+    }
+
+    /// Replace the whole line, with the cursor at the end
+    fn set_text(&mut self, text: &str) {
+        self.text = text.to_string();
+        self.cursor = self.text.len();
+    }
+
+    /// Show an older (or newer) command of the history, keeping the new line to come back to
+    fn recall(&mut self, older: bool, history: &CommandHistory) {
+        let target = match (self.recall, older) {
+            (None, true) => history.entries.len().checked_sub(1),
+            (None, false) => return,
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) => Some(i + 1).filter(|&i| i < history.entries.len())
+        };
+        if self.recall.is_none() {
+            if target.is_none() {
+                return;
+            }
+            self.draft = self.text.clone();
+        }
+        self.recall = target;
+        match target {
+            Some(i) => self.set_text(&history.entries[i].clone()),
+            None => self.set_text(&self.draft.clone())
+        }
+    }
+
+    /// Search the history for the current query, from the newest entry before an index
+    fn search_before(&mut self, before: usize, history: &CommandHistory) {
+        let Some(search) = &mut self.search else {
+            return;
+        };
+        match history.find_before(before, &search.query) {
+            Some(i) => {
+                search.found = Some(i);
+                search.failed = false;
+                let entry = &history.entries[i];
+                self.text = entry.clone();
+                self.cursor = entry.rfind(&search.query).unwrap_or(entry.len());
+            }
+            None => {
+                search.failed = true;
+            }
+        }
+    }
+
+    /// Replace the word being completed with a candidate (or their common start)
+    fn replace_word(&mut self, start: usize, word: &str) {
+        self.text.replace_range(start..self.cursor, word);
+        self.cursor = start + word.len();
+    }
+
+    /// Stop completing, so the listed candidates are cleared on the next draw
+    fn end_completion(&mut self) {
+        if self.completion.take().is_some() {
+            self.relist = true;
+        }
+    }
+
+    /// Paste text at the cursor
+    pub fn paste(&mut self, text: &str) {
+        self.end_completion();
+        self.insert(text);
+    }
+
+    /// Complete the word before the cursor (Tab), or go to the next (or previous) candidate if already completing
+    fn complete(&mut self, backward: bool, complete: &dyn Fn(&str) -> Completions) {
+        if let Some(completion) = &mut self.completion {
+            let count = completion.candidates.len();
+            let index = match (completion.index, backward) {
+                (None, false) => 0,
+                (None, true) => count - 1,
+                (Some(i), false) => (i + 1) % count,
+                (Some(i), true) => (i + count - 1) % count
+            };
+            completion.index = Some(index);
+            let (start, candidate) = (completion.start, completion.candidates[index].clone());
+            self.replace_word(start, &candidate);
+            self.relist = true;
+            return;
+        }
+        let Completions { start, candidates } = complete(&self.text[..self.cursor]);
+        match candidates.as_slice() {
+            [] => {}
+            [candidate] => {
+                // The only candidate, followed by a space to start the next term (unless a directory, to go on into it)
+                let ending = if candidate.ends_with(['/', std::path::MAIN_SEPARATOR]) { "" } else { " " };
+                self.replace_word(start, &format!("{}{}", candidate, ending));
+            }
+            _ => {
+                let common = completion::common_prefix(&candidates).to_string();
+                let mut completion = Completion { start, candidates, index: None };
+                if common.len() > self.cursor - start {
+                    self.replace_word(start, &common);
+                } else {
+                    // Nothing more in common, so start cycling
+                    completion.index = Some(if backward { completion.candidates.len() - 1 } else { 0 });
+                    self.replace_word(start, &completion.candidates[completion.index.unwrap_or(0)].clone());
+                }
+                self.completion = Some(completion);
+                self.relist = true;
+            }
+        }
+    }
+
+    /// Handle a key pressed during a reverse search, returning whether it was used by the search
+    fn handle_search_key(&mut self, key: KeyEvent, history: &CommandHistory) -> bool {
+        let Some(search) = &mut self.search else {
+            return false;
+        };
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let cancel = key.code == KeyCode::Esc || (ctrl && key.code == KeyCode::Char('g'));
+        match key.code {
+            _ if cancel => {
+                // Stop searching, back to the line from before
+                let (text, cursor) = search.original.clone();
+                self.text = text;
+                self.cursor = cursor;
+                self.search = None;
+            }
+            KeyCode::Char('r') if ctrl => {
+                // Find an older match
+                let before = search.found.unwrap_or(history.entries.len());
+                self.search_before(before, history);
+            }
+            KeyCode::Backspace => {
+                search.query.pop();
+                self.search_before(history.entries.len(), history);
+            }
+            KeyCode::Char(c) if !ctrl => {
+                search.query.push(c);
+                // The entry shown may still match
+                let before = search.found.map_or(history.entries.len(), |i| i + 1);
+                self.search_before(before, history);
+            }
+            _ => {
+                // Keep the entry found, and handle the key as usual
+                self.search = None;
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Handle a pressed key, returning how the line ended if it did
+    /// Tab (and Shift-Tab) complete the word before the cursor with the candidates from a function of the text before the cursor
+    pub fn handle_key(&mut self, key: KeyEvent, history: &CommandHistory, complete: &dyn Fn(&str) -> Completions) -> Option<LineEnd> {
+        if self.handle_search_key(key, history) {
+            return None;
+        }
+        if key.code == KeyCode::Tab || key.code == KeyCode::BackTab {
+            self.complete(key.code == KeyCode::BackTab, complete);
+            return None;
+        }
+        self.end_completion();
+        let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+        let alt = key.modifiers.contains(KeyModifiers::ALT);
+        match key.code {
+            KeyCode::Enter => return Some(LineEnd::Submit(self.text.clone())),
+            KeyCode::Esc => return Some(LineEnd::Cancel),
+            KeyCode::Char('c') if ctrl => return Some(LineEnd::Cancel),
+            // Moving the cursor
+            KeyCode::Left if ctrl || alt => self.cursor = self.prev_word_start(),
+            KeyCode::Right if ctrl || alt => self.cursor = self.next_word_end(),
+            KeyCode::Char('b') if alt => self.cursor = self.prev_word_start(),
+            KeyCode::Char('f') if alt => self.cursor = self.next_word_end(),
+            KeyCode::Left => self.cursor = self.prev_boundary(),
+            KeyCode::Char('b') if ctrl => self.cursor = self.prev_boundary(),
+            KeyCode::Right => self.cursor = self.next_boundary(),
+            KeyCode::Char('f') if ctrl => self.cursor = self.next_boundary(),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::Char('a') if ctrl => self.cursor = 0,
+            KeyCode::End => self.cursor = self.text.len(),
+            KeyCode::Char('e') if ctrl => self.cursor = self.text.len(),
+            // Deleting
+            KeyCode::Backspace if ctrl || alt => self.delete_to(self.prev_word_start()),
+            KeyCode::Char('w') if ctrl => self.delete_to(self.prev_word_start()),
+            KeyCode::Char('h') if ctrl => self.delete_to(self.prev_boundary()),
+            KeyCode::Backspace => self.delete_to(self.prev_boundary()),
+            KeyCode::Delete if ctrl || alt => self.delete_to(self.next_word_end()),
+            KeyCode::Char('d') if alt => self.delete_to(self.next_word_end()),
+            KeyCode::Delete => self.delete_to(self.next_boundary()),
+            KeyCode::Char('d') if ctrl => self.delete_to(self.next_boundary()),
+            KeyCode::Char('u') if ctrl => self.delete_to(0),
+            KeyCode::Char('k') if ctrl => self.delete_to(self.text.len()),
+            // The command history
+            KeyCode::Up => self.recall(true, history),
+            KeyCode::Char('p') if ctrl => self.recall(true, history),
+            KeyCode::Down => self.recall(false, history),
+            KeyCode::Char('n') if ctrl => self.recall(false, history),
+            KeyCode::Char('r') if ctrl => {
+                self.search = Some(ReverseSearch {
+                    query: String::new(),
+                    found: None,
+                    failed: false,
+                    original: (self.text.clone(), self.cursor)
+                });
+            }
+            // Typing
+            KeyCode::Char(c) if !ctrl && !alt => self.insert(&c.to_string()),
+            _ => {}
+        }
+        None
+    }
+
+    /// Get the label shown before the text (the prompt, or the query during a reverse search)
+    fn label(&self, prompt: &str) -> String {
+        match &self.search {
+            Some(search) if search.failed => format!("(failed reverse-i-search)`{}': ", search.query),
+            Some(search) => format!("(reverse-i-search)`{}': ", search.query),
+            None => prompt.to_string()
+        }
+    }
+
+    /// List the completion candidates on the lines below the prompt (the status area), highlighting the one in the line
+    /// (or only clear them, if no longer completing)
+    fn draw_listing(&self, y: u16, termwidth: usize, stdout: &mut io::Stdout) -> io::Result<()> {
+        ioutils::clear_status_region(y - 1, stdout)?;
+        let Some(completion) = &self.completion else {
+            return io::Result::Ok(());
+        };
+        // Lay out the candidates (paths by their last part, without quotes) in rows, separated by two spaces: (line, column, shown text)
+        let mut layout: Vec<(usize, usize, &str)> = Vec::new();
+        let (mut line, mut col) = (0, 0);
+        for candidate in &completion.candidates {
+            let unquoted = candidate.trim_matches(['"', '\'']);
+            let trimmed = unquoted.trim_end_matches(['/', std::path::MAIN_SEPARATOR]);
+            let shown = &unquoted[trimmed.rfind(['/', std::path::MAIN_SEPARATOR]).map_or(0, |i| i + 1)..];
+            let width = textwidth::display_width(shown);
+            if col > 0 && col + width > termwidth {
+                line += 1;
+                col = 0;
+            }
+            layout.push((line, col, shown));
+            col += width + 2;
+        }
+        // Cut off the rest if too many
+        let shown = if layout.last().is_none_or(|last| last.0 < LISTING_HEIGHT) {
+            layout.len()
+        } else {
+            layout.iter().filter(|(line, _, _)| line + 1 < LISTING_HEIGHT).count()
+        };
+        for (i, &(line, col, text)) in layout.iter().enumerate().take(shown) {
+            if completion.index == Some(i) {
+                printstyl(col as u16, y + 1 + line as u16, text.to_string().reverse(), stdout)?;
+            } else {
+                printat(col as u16, y + 1 + line as u16, text, stdout)?;
+            }
+        }
+        if shown < layout.len() {
+            printat(0, y + LISTING_HEIGHT as u16, &format!("… ({} more)", layout.len() - shown), stdout)?;
+        }
+        io::Result::Ok(())
+    }
+
+    /// Draw the line after a prompt on a line of the terminal, scrolled so the cursor is shown, with the terminal cursor on it
+    /// (and the completion candidates below it, if they changed)
+    pub fn draw(&mut self, prompt: &str, y: u16, stdout: &mut io::Stdout) -> io::Result<()> {
+        let label = self.label(prompt);
+        let labelwidth = textwidth::display_width(&label);
+        let termwidth = terminal::size().map(|(w, _)| w as usize).unwrap_or(80);
+        let width = termwidth.saturating_sub(labelwidth + 1).max(1);
+        // Scroll to the cursor
+        let cursorcol = textwidth::display_width(&self.text[..self.cursor]);
+        if cursorcol < self.scroll {
+            self.scroll = cursorcol;
+        } else if cursorcol >= self.scroll + width {
+            self.scroll = cursorcol + 1 - width;
+        }
+        // Get the text in view (whole grapheme clusters only)
+        let mut shown = String::new();
+        let mut col = 0;
+        for grapheme in self.text.graphemes(true) {
+            let w = textwidth::display_width(grapheme);
+            if col >= self.scroll && col + w <= self.scroll + width {
+                shown.push_str(grapheme);
+            }
+            col += w;
+        }
+        if self.relist {
+            self.draw_listing(y, termwidth, stdout)?;
+            self.relist = false;
+        }
+        queue!(stdout, cursor::MoveTo(0, y), terminal::Clear(terminal::ClearType::CurrentLine))?;
+        printat(0, y, &label, stdout)?;
+        printat(labelwidth as u16, y, &shown, stdout)?;
+        queue!(stdout, cursor::MoveTo((labelwidth + cursorcol - self.scroll) as u16, y))?;
+        flush(stdout)?;
+        io::Result::Ok(())
+    }
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Read a line typed after a prompt on a line of the terminal (raw mode must be enabled), or None if cancelled
+/// Entered lines are added to the command history, and Tab completes words with the candidates from a function (see handle_key)
+/// When the terminal is resized, a function redraws what is above the prompt and returns the prompt's new line
+pub fn read_line(
+    prompt: &str,
+    y: u16,
+    history: &mut CommandHistory,
+    complete: &dyn Fn(&str) -> Completions,
+    redraw: &dyn Fn(&mut io::Stdout) -> io::Result<u16>,
+    stdout: &mut io::Stdout
+) -> io::Result<Option<String>> {
+    let mut editor = LineEditor::new();
+    let mut y = y;
+    loop {
+        editor.draw(prompt, y, stdout)?;
+        let end = match ioutils::read_input() {
+            Input::Key(key) => editor.handle_key(key, history, complete),
+            Input::Paste(text) => {
+                editor.paste(&text);
+                None
+            }
+            Input::Resize => {
+                y = redraw(stdout)?;
+                editor.relist = true;
+                None
+            }
+        };
+        match end {
+            Some(LineEnd::Submit(line)) => {
+                history.push(&line);
+                return io::Result::Ok(Some(line));
+            }
+            Some(LineEnd::Cancel) => {
+                return io::Result::Ok(None);
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    /// Type keys into an editor, returning how the line ended (if it did)
+    fn press(editor: &mut LineEditor, keys: &[KeyEvent], history: &CommandHistory) -> Option<LineEnd> {
+        let no_completions = |line: &str| Completions { start: line.len(), candidates: Vec::new() };
+        keys.iter().find_map(|&k| editor.handle_key(k, history, &no_completions))
+    }
+
+    fn history(entries: &[&str]) -> CommandHistory {
+        CommandHistory { entries: entries.iter().map(|e| e.to_string()).collect(), savepath: PathBuf::new() }
+    }
+
+    #[test]
+    fn moves_and_deletes_by_word() {
+        let history = history(&[]);
+        let mut editor = LineEditor::new();
+        editor.insert("sort by  2 héllo");
+        press(&mut editor, &[key(KeyCode::Left, KeyModifiers::CONTROL)], &history);
+        assert_eq!(editor.cursor, "sort by  2 ".len());
+        press(&mut editor, &[key(KeyCode::Char('b'), KeyModifiers::ALT)], &history);
+        assert_eq!(editor.cursor, "sort by  ".len());
+        press(&mut editor, &[key(KeyCode::Char('w'), KeyModifiers::CONTROL)], &history);
+        assert_eq!((editor.text.as_str(), editor.cursor), ("sort 2 héllo", "sort ".len()));
+        press(&mut editor, &[key(KeyCode::Right, KeyModifiers::ALT)], &history);
+        assert_eq!(editor.cursor, "sort 2".len());
+        press(&mut editor, &[key(KeyCode::Char('d'), KeyModifiers::ALT)], &history);
+        assert_eq!(editor.text, "sort 2");
+        press(&mut editor, &[key(KeyCode::Home, KeyModifiers::NONE), key(KeyCode::Char('f'), KeyModifiers::ALT)], &history);
+        assert_eq!(editor.cursor, "sort".len());
+        press(&mut editor, &[key(KeyCode::Char('k'), KeyModifiers::CONTROL)], &history);
+        assert_eq!(editor.text, "sort");
+    }
+
+    #[test]
+    fn moves_by_grapheme() {
+        let history = history(&[]);
+        let mut editor = LineEditor::new();
+        editor.insert("ae\u{301}x");
+        press(&mut editor, &[key(KeyCode::Left, KeyModifiers::NONE), key(KeyCode::Backspace, KeyModifiers::NONE)], &history);
+        assert_eq!((editor.text.as_str(), editor.cursor), ("ax", 1));
+    }
+
+    #[test]
+    fn recalls_history_keeping_the_draft() {
+        let history = history(&["open a.csv", "save"]);
+        let mut editor = LineEditor::new();
+        editor.insert("ne");
+        press(&mut editor, &[key(KeyCode::Up, KeyModifiers::NONE)], &history);
+        assert_eq!(editor.text, "save");
+        press(&mut editor, &[key(KeyCode::Up, KeyModifiers::NONE), key(KeyCode::Up, KeyModifiers::NONE)], &history);
+        assert_eq!((editor.text.as_str(), editor.cursor), ("open a.csv", 10));
+        press(&mut editor, &[key(KeyCode::Char('n'), KeyModifiers::CONTROL)], &history);
+        assert_eq!(editor.text, "save");
+        press(&mut editor, &[key(KeyCode::Down, KeyModifiers::NONE)], &history);
+        assert_eq!(editor.text, "ne");
+        press(&mut editor, &[key(KeyCode::Down, KeyModifiers::NONE)], &history);
+        assert_eq!(editor.text, "ne");
+        let end = press(&mut editor, &[key(KeyCode::Char('w'), KeyModifiers::NONE), key(KeyCode::Enter, KeyModifiers::NONE)], &history);
+        assert_eq!(end, Some(LineEnd::Submit("new".to_string())));
+    }
+
+    #[test]
+    fn searches_history_in_reverse() {
+        let history = history(&["open a.csv", "sort 1", "open b.csv", "save"]);
+        let mut editor = LineEditor::new();
+        editor.insert("draft");
+        let search: Vec<KeyEvent> = [key(KeyCode::Char('r'), KeyModifiers::CONTROL)].into_iter()
+            .chain("open".chars().map(|c| key(KeyCode::Char(c), KeyModifiers::NONE)))
+            .collect();
+        press(&mut editor, &search, &history);
+        assert_eq!(editor.text, "open b.csv");
+        assert_eq!(editor.label("> "), "(reverse-i-search)`open': ");
+        press(&mut editor, &[key(KeyCode::Char('r'), KeyModifiers::CONTROL)], &history);
+        assert_eq!(editor.text, "open a.csv");
+        press(&mut editor, &[key(KeyCode::Char('r'), KeyModifiers::CONTROL)], &history);
+        assert_eq!(editor.label("> "), "(failed reverse-i-search)`open': ");
+        // Cancelling goes back to the line from before
+        press(&mut editor, &[key(KeyCode::Esc, KeyModifiers::NONE)], &history);
+        assert_eq!((editor.text.as_str(), editor.label("> ").as_str()), ("draft", "> "));
+        // Other keys keep the entry found, and are handled as usual
+        press(&mut editor, &search, &history);
+        press(&mut editor, &[key(KeyCode::Backspace, KeyModifiers::NONE)], &history);
+        assert_eq!(editor.label("> "), "(reverse-i-search)`ope': ");
+        press(&mut editor, &[key(KeyCode::End, KeyModifiers::NONE), key(KeyCode::Backspace, KeyModifiers::NONE)], &history);
+        let end = press(&mut editor, &[key(KeyCode::Enter, KeyModifiers::NONE)], &history);
+        assert_eq!(end, Some(LineEnd::Submit("open b.cs".to_string())));
+    }
+}
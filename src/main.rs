@@ -6,15 +6,29 @@ pub mod configdata;
 pub mod render;
 pub mod ioutils;
 pub mod command;
+pub mod csvformat;
+pub mod formula;
+pub mod depgraph;
+pub mod sortkey;
+pub mod search;
+pub mod register;
+pub mod filter;
+pub mod textwidth;
+pub mod screen;
+pub mod history;
+pub mod lineeditor;
+pub mod completion;
 use ioutils::{
     printat,
     clear,
-    read_key,
+    read_input,
     set_raw_mode,
     print_input_word,
-    print_command_prompt,
-    print_status_message
+    COMMAND_PROMPT,
+    print_status_message,
+    copy_to_system_clipboard
 };
+use command::CommandName;
 use std::{ cmp, io, env };
 
 /*
@@ -26,7 +40,6 @@ TODOS:
     - Misc. scattered todos
     - Rerender after ALL commands like save, delete, etc.
     - Refactor the main file
-    - Performance lag in large window
     - Icon for the app exe
     - Create a release on GitHub with binaries (release build)
     - Fix: tmux rendering issues (tmux on arch on wsl on windows terminal)
@@ -51,11 +64,12 @@ fn main() -> io::Result<()> {
     // Initialize data
     let mut config = configdata::ConfigData::new();
     let mut data = sheetdata::SheetData::new();
+    data.set_frozen((frozen_config(&config, "freezerows"), frozen_config(&config, "freezecolumns")));
 
     // If there is a command line argument, try to load that file
     let args: Vec<String> = env::args().collect();
     if args.len() > 1 {
-        data.load_file(&args[1]);
+        let _ = data.load_file(&args[1], &config);
     }
 
     // Start the command cycle
@@ -64,34 +78,264 @@ fn main() -> io::Result<()> {
     io::Result::Ok(())
 }
 
+/// Get the number of rows or columns to freeze from the config
+fn frozen_config(config: &configdata::ConfigData, key: &str) -> usize {
+    config.get_value(key).unwrap_or(0).try_into().unwrap_or(0)
+}
+
 /// Get the vertical coordinate of the first line below the main sheet (the line for inputting cells)
 /// This value, vstart, is used for printing information below the sheet
 fn vertical_coord_of_input(config: &configdata::ConfigData, data: &sheetdata::SheetData) -> u16 {
-    let selectedcoords = data.selected().unwrap_or((0, 0));
-    let viewheight: usize = config.get_value("viewcellsheight").unwrap_or(10).try_into().unwrap_or(10);
-    let vtop: usize = cmp::max(selectedcoords.0.saturating_sub(viewheight / 2), 0);
-    let vbottom: usize = cmp::min(vtop + viewheight, data.bounds().0);
     const VERTICAL_EXTRA: usize = 4;
-    (vbottom - vtop + VERTICAL_EXTRA) as u16
+    (render::shown_rows(config, data).len() + VERTICAL_EXTRA) as u16
 }
 
 /// Command cycle
 fn command_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::SheetData, stdout: &mut io::Stdout) -> io::Result<()> {
+    let mut commandhistory = lineeditor::CommandHistory::new();
     loop {
         let vstart = vertical_coord_of_input(config, data);
         // TODO: better cycle appearance
-        print_command_prompt(vstart, stdout)?;
+        // The command is typed in raw mode, with the line editor
+        set_raw_mode(true)?;
+        let line = lineeditor::read_line(
+            COMMAND_PROMPT,
+            vstart + 1,
+            &mut commandhistory,
+            &|line| completion::complete(line, config),
+            &|stdout| {
+                // Lay out the sheet again for the new size, with the prompt below it
+                render::render(config, data, &mut screen::Screen::new(), stdout)?;
+                Ok(vertical_coord_of_input(config, data) + 1)
+            },
+            stdout
+        )?;
+        // The terminal may have been resized while typing
+        let vstart = vertical_coord_of_input(config, data);
 
         // Raw mode should be disabled for commands
         set_raw_mode(false)?;
 
-        let mut uin = String::new();
-        std::io::stdin().read_line(&mut uin).expect("Failed to read line");
-        let user_command = command::Command::from(&uin);
+        let Some(uin) = line else {
+            // Cancelled: start over with an empty prompt
+            print_status_message(vstart, stdout, "")?;
+            continue;
+        };
+        // Search: "/pattern" forward, or "?pattern" backward (the rest of the line is the pattern)
+        let rawline = uin.trim_end_matches(['\n', '\r']);
+        let rawline = rawline.strip_prefix(':').unwrap_or(rawline);
+        if rawline.starts_with('/') || rawline.starts_with('?') {
+            match data.start_search(&rawline[1..], rawline.starts_with('?')) {
+                Err(e) => {
+                    print_status_message(vstart, stdout, &e)?;
+                },
+                Ok(()) => {
+                    // Start control cycle
+                    control_cycle(config, data, stdout)?;
+                }
+            }
+            continue;
+        }
+        // Substitute: "[range]s/pattern/replacement/[flags]"
+        if let Some(parsed) = search::Substitution::parse_vim(rawline) {
+            match parsed {
+                Err(e) => {
+                    print_status_message(vstart, stdout, &e)?;
+                },
+                Ok((scope, substitution)) => {
+                    run_replace(config, data, scope, &substitution);
+                    // Start control cycle
+                    control_cycle(config, data, stdout)?;
+                }
+            }
+            continue;
+        }
+        let user_command = match command::Command::parse(&uin) {
+            Ok(user_command) => user_command,
+            Err(e) => {
+                print_status_message(vstart, stdout, &e.to_string())?;
+                continue;
+            }
+        };
+        let name = user_command.name();
+        // Commands with any number of terms
+        if name == Some(CommandName::Sort) {
+            match sortkey::SortOptions::parse(&user_command.terms_from(1)) {
+                Err(e) => {
+                    print_status_message(vstart, stdout, &e)?;
+                },
+                Ok(options) => {
+                    if run_sort(config, data, &options) {
+                        // Start control cycle
+                        control_cycle(config, data, stdout)?;
+                    } else {
+                        print_status_message(vstart, stdout, "Could not sort (check the bounds and sort keys).")?;
+                    }
+                }
+            }
+            continue;
+        }
+        if name == Some(CommandName::Fill) {
+            // Fill the selected range: "fill down", "fill right", or "fill {value}"
+            let fill = match user_command.terms_from(1).as_slice() {
+                ["down" | "d"] => Some(sheetdata::Fill::Down),
+                ["right" | "r"] => Some(sheetdata::Fill::Right),
+                [] => None,
+                values => Some(sheetdata::Fill::Value(values.join(" ")))
+            };
+            match fill {
+                Some(fill) => {
+                    data.fill_selection(&fill, config);
+                    // Start control cycle
+                    control_cycle(config, data, stdout)?;
+                },
+                None => {
+                    print_status_message(vstart, stdout, "Usage: fill down | fill right | fill {value}")?;
+                }
+            }
+            continue;
+        }
+        if name == Some(CommandName::Filter) {
+            // Filter rows: "filter {column} {operator} {value}", "filter clear", or "filter" (list the filters)
+            match user_command.terms_from(1).as_slice() {
+                [] => {
+                    let list: Vec<String> = data.filters().iter().map(|f| f.to_string()).collect();
+                    if list.is_empty() {
+                        print_status_message(vstart, stdout, "No filters.")?;
+                    } else {
+                        print_status_message(vstart, stdout, &list.join("\n"))?;
+                    }
+                },
+                ["clear" | "c"] => {
+                    data.clear_filters();
+                    // Start control cycle
+                    control_cycle(config, data, stdout)?;
+                },
+                terms => match filter::Filter::parse(terms).and_then(|f| data.add_filter(f)) {
+                    Err(e) => {
+                        print_status_message(vstart, stdout, &e)?;
+                    },
+                    Ok(()) => {
+                        // Start control cycle
+                        control_cycle(config, data, stdout)?;
+                    }
+                }
+            }
+            continue;
+        }
+        if matches!(name, Some(CommandName::Earlier | CommandName::Later)) {
+            // Go back or forward through the undo history, across branches: "earlier [{count} | {time}]" (ex. "earlier 5m")
+            let travel = match user_command.terms_from(1).as_slice() {
+                [] => Some(history::Travel::States(1)),
+                [amount] => history::Travel::parse(amount),
+                _ => None
+            };
+            match travel {
+                Some(travel) => {
+                    data.travel_history(travel, name == Some(CommandName::Later));
+                    // Start control cycle
+                    control_cycle(config, data, stdout)?;
+                },
+                None => {
+                    print_status_message(vstart, stdout, "Usage: earlier | later [{count} | {number}s | {number}m | {number}h | {number}d]")?;
+                }
+            }
+            continue;
+        }
+        if let Some(freeze @ (CommandName::Freeze | CommandName::Unfreeze)) = name {
+            // Freeze rows and columns: "freeze {rows} [{columns}]", or "freeze off"/"unfreeze"
+            let frozen = match (freeze, user_command.terms_from(1).as_slice()) {
+                (CommandName::Unfreeze, []) | (CommandName::Freeze, ["off"]) => Some((0, 0)),
+                (CommandName::Freeze, [rows]) => rows.parse().ok().map(|rows| (rows, 0)),
+                (CommandName::Freeze, [rows, cols]) => rows.parse().ok().zip(cols.parse().ok()),
+                _ => None
+            };
+            match frozen {
+                Some(frozen) => {
+                    data.set_frozen(frozen);
+                    // Start control cycle
+                    control_cycle(config, data, stdout)?;
+                },
+                None => {
+                    print_status_message(vstart, stdout, "Usage: freeze {rows} [{columns}] | freeze off")?;
+                }
+            }
+            continue;
+        }
+        if let Some(sizing @ (CommandName::Width | CommandName::Autofit)) = name {
+            // Column widths: "width {width}" or "width" (default width) for the selected columns,
+            // or "autofit [{max width}]" to fit the selected columns (or all columns) to their values
+            let columns: Vec<usize> = match data.selection_bounds() {
+                Some((topleft, bottomright)) if data.has_range() || sizing == CommandName::Width => (topleft.1..=bottomright.1).collect(),
+                _ => (0..data.bounds().1).collect()
+            };
+            let autofitmax: usize = config.get_value("autofitmax").unwrap_or(30).try_into().unwrap_or(30);
+            let done = match (sizing, user_command.terms_from(1).as_slice()) {
+                (CommandName::Width, []) => {
+                    columns.iter().for_each(|&col| data.set_column_width(col, None));
+                    true
+                },
+                (CommandName::Width, [width]) => match width.parse::<usize>() {
+                    Ok(width) => {
+                        columns.iter().for_each(|&col| data.set_column_width(col, Some(width)));
+                        true
+                    },
+                    Err(_) => false
+                },
+                (CommandName::Autofit, []) => {
+                    data.autofit_columns(&columns, autofitmax);
+                    true
+                },
+                (CommandName::Autofit, [maxwidth]) => match maxwidth.parse::<usize>() {
+                    Ok(maxwidth) => {
+                        data.autofit_columns(&columns, maxwidth);
+                        true
+                    },
+                    Err(_) => false
+                },
+                _ => false
+            };
+            if done {
+                // Start control cycle
+                control_cycle(config, data, stdout)?;
+            } else {
+                print_status_message(vstart, stdout, "Usage: width [{width}] | autofit [{max width}]")?;
+            }
+            continue;
+        }
+        if name == Some(CommandName::Replace) {
+            // Plain text replace: "replace {find} {replacement} [column | {row start} {row end}]"
+            let scope = match user_command.terms_from(3).as_slice() {
+                [] => Some(search::ReplaceScope::Sheet),
+                ["column" | "col" | "c"] => Some(search::ReplaceScope::CurrentColumn),
+                ["row" | "r"] => Some(search::ReplaceScope::CurrentRow),
+                [start, end] => match (start.parse(), end.parse()) {
+                    (Ok(start), Ok(end)) => Some(search::ReplaceScope::Rows(start, end)),
+                    _ => None
+                },
+                _ => None
+            };
+            match (scope, user_command.len() >= 3) {
+                (Some(scope), true) => match search::Substitution::plain(user_command.term(1), user_command.term(2)) {
+                    Err(e) => {
+                        print_status_message(vstart, stdout, &e)?;
+                    },
+                    Ok(substitution) => {
+                        run_replace(config, data, scope, &substitution);
+                        // Start control cycle
+                        control_cycle(config, data, stdout)?;
+                    }
+                },
+                _ => {
+                    print_status_message(vstart, stdout, "Usage: replace {find} {replacement} [column | row | {row start} {row end}]")?;
+                }
+            }
+            continue;
+        }
         match user_command.len() {
             1 => {
-                match user_command.term(0) {
-                    "quit" | "q" => {
+                match name {
+                    Some(CommandName::Quit) => {
                         // Quit
                         if data.unsaved {
                             // Quit confirmation
@@ -105,16 +349,16 @@ fn command_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::Shee
                             break;
                         }
                     },
-                    "quit!" | "q!" => {
+                    Some(CommandName::ForceQuit) => {
                         // Force quit
                         break;
                     }
-                    "edit" | "e" => {
+                    Some(CommandName::Edit) => {
                         // Back to editing the file
                         // Start control cycle
                         control_cycle(config, data, stdout)?;
                     },
-                    "new" => {
+                    Some(CommandName::New) => {
                         // New file: load a blank default vector
                         if data.unsaved {
                             // New confirmation
@@ -130,58 +374,66 @@ fn command_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::Shee
                             control_cycle(config, data, stdout)?;
                         }
                     },
-                    "new!" => {
+                    Some(CommandName::ForceNew) => {
                         // New file: load a blank default vector
                         data.load_vector(&vec![vec!["".to_string(); 16]; 16]);
                         // Start control cycle
                         control_cycle(config, data, stdout)?;
                     },
-                    "save" | "w" => {
+                    Some(CommandName::Save) => {
                         // Save the file to the same path, if possible
                         // TODO: override not-saving-if-unedited with '!'
                         // TODO: re-render to show updated filename, etc.
-                        let save_success = data.save_file(&data.file_path.clone());
+                        let save_success = data.save_file(&data.file_path.clone(), config);
                         if !save_success {
                             print_status_message(vstart, stdout, "Error saving file.")?;
                         } else {
                             print_status_message(vstart, stdout, "Saved file.")?;
-                            render::render(config, data, stdout)?;
+                            render::render(config, data, &mut screen::Screen::new(), stdout)?;
                         }
                     },
-                    "path" => {
+                    Some(CommandName::Path) => {
                         // Display the file path (filename with path as entered)
                         print_status_message(vstart, stdout, &data.file_path)?;
                     },
-                    "config" => {
+                    Some(CommandName::Config) => {
                         // Display all the config items
                         print_status_message(vstart, stdout, &config.display())?;
                     },
-                    "sort" => {
-                        // Sort
-                        data.sort_column(data.selected().unwrap_or((0, 0)).1, config);
+                    Some(CommandName::NoHighlight) => {
+                        // Stop highlighting search matches
+                        data.clear_search();
                         // Start control cycle
                         control_cycle(config, data, stdout)?;
                     },
-                    "undo" | "u" => {
+                    Some(CommandName::Delimiter) => {
+                        // Display the delimiter of the current file
+                        print_status_message(vstart, stdout, &format!("Delimiter: {}", csvformat::delimiter_name(data.delimiter)))?;
+                    },
+                    Some(CommandName::Undo) => {
                         // Undo
                         data.undo();
                         // Start control cycle
                         control_cycle(config, data, stdout)?;
                     },
-                    "redo" | "r" => {
+                    Some(CommandName::Redo) => {
                         // Redo
                         data.redo();
                         // Start control cycle
                         control_cycle(config, data, stdout)?;
                     },
+                    Some(CommandName::UndoList) => {
+                        // Display the states of the undo history
+                        print_status_message(vstart, stdout, &fmt_undo_list(data))?;
+                    },
                     _ => {
                         print_status_message(vstart, stdout, "Unknown command.")?;
                     }
                 }
             },
             2 => {
-                match user_command.term(0) {
-                    "open" | "e" => {
+                match name {
+                    Some(CommandName::Open | CommandName::Edit) => {
                         // Load the file
                         if data.unsaved {
                             // Load confirmation
@@ -191,46 +443,61 @@ fn command_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::Shee
                                 "use \"open!\" or \"e!\" instead"
                             ))?;
                         } else {
-                            let load_success = data.load_file(user_command.term(1));
-                            if !load_success {
-                                print_status_message(vstart, stdout, "Error opening file.")?;
-                                // TODO: handle error better
-                            } else {
-                                // Start the control cycle
-                                control_cycle(config, data, stdout)?;
+                            match data.load_file(user_command.term(1), config) {
+                                Err(e) => {
+                                    print_status_message(vstart, stdout, &format!("Error opening file: {}", e))?;
+                                },
+                                Ok(()) => {
+                                    // Start the control cycle
+                                    control_cycle(config, data, stdout)?;
+                                }
                             }
                         }
                     },
-                    "open!" | "e!" => {
+                    Some(CommandName::ForceOpen) => {
                         // Force load the file
-                        let load_success = data.load_file(user_command.term(1));
-                        if !load_success {
-                            print_status_message(vstart, stdout, "Error opening file.")?;
-                        } else {
-                            // Start the control cycle
-                            control_cycle(config, data, stdout)?;
+                        match data.load_file(user_command.term(1), config) {
+                            Err(e) => {
+                                print_status_message(vstart, stdout, &format!("Error opening file: {}", e))?;
+                            },
+                            Ok(()) => {
+                                // Start the control cycle
+                                control_cycle(config, data, stdout)?;
+                            }
                         }
                     },
-                    "save" | "w" => {
+                    Some(CommandName::Save) => {
                         // Save the file
-                        let save_success = data.save_file(user_command.term(1));
+                        let save_success = data.save_file(user_command.term(1), config);
                         if !save_success {
                             print_status_message(vstart, stdout, "Error saving file.")?;
                         } else {
                             print_status_message(vstart, stdout, "Saved file.")?;
                             // TODO: implement the rerender after save (remove *) for all/most commands
-                            render::render(config, data, stdout)?;
+                            render::render(config, data, &mut screen::Screen::new(), stdout)?;
                         }
                     },
-                    "delete" | "d" => {
+                    Some(CommandName::Delimiter) => {
+                        // Set the delimiter used when saving the current file
+                        match csvformat::parse_delimiter(user_command.term(1)) {
+                            Some(delimiter) => {
+                                data.set_delimiter(delimiter);
+                                print_status_message(vstart, stdout, &format!("Delimiter: {}", csvformat::delimiter_name(data.delimiter)))?;
+                            },
+                            None => {
+                                print_status_message(vstart, stdout, "Unknown delimiter (use comma, tab, semicolon, pipe, or a single character).")?;
+                            }
+                        }
+                    },
+                    Some(CommandName::Delete) => {
                         match user_command.term(1) {
                             "row" | "r" => {
-                                data.delete_row(data.selected().unwrap_or((0, 0)).0, config);
+                                data.delete_selected_rows(config);
                                 // Start control cycle
                                 control_cycle(config, data, stdout)?;
                             },
                             "column" | "col" | "c" => {
-                                data.delete_column(data.selected().unwrap_or((0, 0)).1, config);
+                                data.delete_selected_columns(config);
                                 // Start control cycle
                                 control_cycle(config, data, stdout)?;
                             },
@@ -239,7 +506,7 @@ fn command_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::Shee
                             }
                         }
                     },
-                    "insert" | "o" | "i" => {
+                    Some(CommandName::Insert) => {
                         match user_command.term(1) {
                             "row" | "r" => {
                                 data.insert_row(data.selected().unwrap_or((0, 0)).0, config);
@@ -262,26 +529,50 @@ fn command_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::Shee
                 }
             },
             3 => {
-                match user_command.term(0) {
-                    "nav" | "g" => {
+                match name {
+                    Some(CommandName::Open | CommandName::Edit | CommandName::ForceOpen) => {
+                        // Load the file with an explicit delimiter
+                        let force = name == Some(CommandName::ForceOpen);
+                        if data.unsaved && !force {
+                            // Load confirmation
+                            print_status_message(vstart, stdout, concat!(
+                                "You have unsaved changes to this file.\n",
+                                "If you want to switch to a new file without saving, ",
+                                "use \"open!\" or \"e!\" instead"
+                            ))?;
+                        } else if let Some(delimiter) = csvformat::parse_delimiter(user_command.term(2)) {
+                            match data.load_file_with_delimiter(user_command.term(1), Some(delimiter), config) {
+                                Err(e) => {
+                                    print_status_message(vstart, stdout, &format!("Error opening file: {}", e))?;
+                                },
+                                Ok(()) => {
+                                    // Start the control cycle
+                                    control_cycle(config, data, stdout)?;
+                                }
+                            }
+                        } else {
+                            print_status_message(vstart, stdout, "Unknown delimiter (use comma, tab, semicolon, pipe, or a single character).")?;
+                        }
+                    },
+                    Some(CommandName::Nav) => {
                         // Navigate to a cell (command[2], command[1])
                         data.set_selected_coords((user_command.term(2).parse().unwrap_or(0), user_command.term(1).parse().unwrap_or(0)));
                         // Start the control cycle
                         control_cycle(config, data, stdout)?;
                     },
-                    "config" => {
+                    Some(CommandName::Config) => {
                         // Set a config to a value
                         config.set_value(user_command.term(1), user_command.term(2).parse().unwrap_or(2));
+                        // Freezing applies right away
+                        match user_command.term(1) {
+                            "freezerows" => data.set_frozen((frozen_config(config, "freezerows"), data.frozen().1)),
+                            "freezecolumns" => data.set_frozen((data.frozen().0, frozen_config(config, "freezecolumns"))),
+                            _ => {}
+                        }
                         // Display all the config items
                         print_status_message(vstart, stdout, &config.display())?;
                     },
-                    "sort" => {
-                        // Sort column over region command[1]..=command[2]
-                        data.sort_column_bounded(data.selected().unwrap_or((0, 0)).1, user_command.term(1).parse().unwrap_or(0), user_command.term(2).parse().unwrap_or(data.bounds().0 - 1), config);
-                        // Start control cycle
-                        control_cycle(config, data, stdout)?;
-                    },
-                    "insert" | "o" | "i" => {
+                    Some(CommandName::Insert) => {
                         match user_command.term(2) {
                             "post" | "p" => {
                                 match user_command.term(1) {
@@ -318,33 +609,217 @@ fn command_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::Shee
     io::Result::Ok(())
 }
 
+/// Sort the current sheet with the options of a sort command, returning whether successful
+fn run_sort(config: &configdata::ConfigData, data: &mut sheetdata::SheetData, options: &sortkey::SortOptions) -> bool {
+    let selected = data.selected().unwrap_or((0, 0));
+    // By default, sort by the selected column (or row) over the whole sheet
+    let (keyindex, len) = if options.horizontal {
+        (selected.0, data.bounds().1)
+    } else {
+        (selected.1, data.bounds().0)
+    };
+    let keys = if options.keys.is_empty() {
+        vec![sortkey::SortKey::new(keyindex)]
+    } else {
+        options.keys.clone()
+    };
+    // By default, sort the rows (or columns) of the selected range if there is one, or else the whole sheet
+    let selectedrange = match data.selection_bounds() {
+        Some((topleft, bottomright)) if data.has_range() => {
+            if options.horizontal { (topleft.1, bottomright.1) } else { (topleft.0, bottomright.0) }
+        }
+        _ => (0, len.saturating_sub(1))
+    };
+    let (mut start, end) = options.range.unwrap_or(selectedrange);
+    if options.header {
+        start = cmp::max(start, 1);
+    }
+    data.clear_range();
+    if options.horizontal {
+        data.sort_columns(start, end, &keys, config)
+    } else {
+        data.sort_rows(start, end, &keys, config)
+    }
+}
+
+/// Format the states of the undo history, oldest first, one per line
+/// (ex. `> 4  after 2  10s ago  saved`, where `>` marks the current state)
+fn fmt_undo_list(data: &sheetdata::SheetData) -> String {
+    data.history_states().iter().map(|state| {
+        let parent = match state.parent {
+            Some(parent) => format!("after {}", parent),
+            None => "base".to_string()
+        };
+        let mut line = format!("{} {:>3}  {:<9}  {}", if state.current { '>' } else { ' ' }, state.id, parent, state.age());
+        if state.saved {
+            line.push_str("  saved");
+        }
+        if state.leaf && state.parent.is_some() {
+            line.push_str("  (branch end)");
+        }
+        line
+    }).collect::<Vec<String>>().join("\n")
+}
+
+/// Get the kind of register contents the selected range makes (whole rows in visual line mode, cells otherwise)
+fn selection_register_kind(data: &sheetdata::SheetData) -> register::RegisterKind {
+    if data.has_range() && data.selection_mode() == sheet::SelectionMode::Rows {
+        register::RegisterKind::Rows
+    } else {
+        register::RegisterKind::Cells
+    }
+}
+
+/// Select a count of rows, columns, or cells (rightwards) from the selected cell, unless a range is already selected
+fn select_count(data: &mut sheetdata::SheetData, kind: register::RegisterKind, count: isize) {
+    let Some(selected) = data.selected().filter(|_| !data.has_range()) else {
+        return;
+    };
+    // Stop at the edge of the sheet
+    let rowcount = cmp::min(count as usize, data.bounds().0 - selected.0);
+    let colcount = cmp::min(count as usize, data.bounds().1 - selected.1);
+    match kind {
+        register::RegisterKind::Rows => {
+            data.start_range(sheet::SelectionMode::Rows);
+            data.set_selected_coords((selected.0 + rowcount - 1, selected.1));
+        }
+        register::RegisterKind::Columns | register::RegisterKind::Cells => {
+            data.start_range(sheet::SelectionMode::Block);
+            data.set_selected_coords((selected.0, selected.1 + colcount - 1));
+        }
+    }
+}
+
+/// Start selecting a range in a mode, or stop if already selecting one in that mode (vim's visual modes)
+fn toggle_range(data: &mut sheetdata::SheetData, mode: sheet::SelectionMode) {
+    if !data.has_range() {
+        data.start_range(mode);
+    } else if data.selection_mode() == mode {
+        data.clear_range();
+    } else {
+        data.set_selection_mode(mode);
+    }
+}
+
+/// Replace in the current sheet, reporting the number of cells changed in the status message
+fn run_replace(config: &configdata::ConfigData, data: &mut sheetdata::SheetData, scope: search::ReplaceScope, substitution: &search::Substitution) {
+    let changed = data.replace(scope, substitution, config);
+    data.status_message = match changed {
+        0 => "Pattern not found.".to_string(),
+        1 => "Replaced in 1 cell.".to_string(),
+        n => format!("Replaced in {} cells.", n)
+    };
+}
+
+/// Render again without ending the input in progress (after a resize or a paste), printing what is being typed back
+/// Returns the new vstart
+fn redraw_in_place(
+    config: &mut configdata::ConfigData,
+    data: &mut sheetdata::SheetData,
+    screen: &mut screen::Screen,
+    vstart: u16,
+    inputword: &str,
+    stdout: &mut io::Stdout
+) -> io::Result<u16> {
+    // The input word was printed straight to the terminal, so redraw its line
+    screen.forget_line(vstart);
+    render::render(config, data, screen, stdout)?;
+    data.status_message.clear();
+    let vstart = vertical_coord_of_input(config, data);
+    if !inputword.is_empty() {
+        print_input_word(vstart, stdout, inputword)?;
+    }
+    Ok(vstart)
+}
+
 /// Input cycle function (when in "normal"/non-command mode)
 fn control_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::SheetData, stdout: &mut io::Stdout) -> io::Result<()> {
+    // Only the cells that changed are redrawn after each input (the first frame is drawn in full)
+    let mut screen = screen::Screen::new();
+    set_raw_mode(true)?;
     loop {
-        let vstart = vertical_coord_of_input(config, data);
+        let mut vstart = vertical_coord_of_input(config, data);
+        // Copy what was just yanked or cut to the system clipboard
+        if let Some(text) = data.take_clipboard_text() {
+            if config.get_value("osc52").unwrap_or(1) != 0 {
+                copy_to_system_clipboard(&text, stdout)?;
+            }
+        }
         // Render
-        render::render(config, data, stdout)?;
+        render::render(config, data, &mut screen, stdout)?;
+        data.status_message.clear();
 
         // Input loop until a rerender
         let mut inputword: String = String::new();
         let mut insertmode: bool = false;
         let mut priorcapture: char = ' ';
+        let mut register: Option<char> = None; // Register chosen with '"' (vim mode)
         let mut repeat_times: u32 = 0;
+        let mut searchprefix: Option<char> = None; // '/' or '?' while typing a search pattern (vim mode)
         loop {
             let mut endinput: bool = true;
             // Get and take action on input
-            let ink = read_key();
+            let inkey = match read_input() {
+                ioutils::Input::Key(k) => k,
+                ioutils::Input::Paste(text) => {
+                    // Pasted text: type a single value into the cell (or search) being edited,
+                    // or else spread it across the cells from the selected cell
+                    let value = text.trim_end_matches(['\n', '\r']);
+                    let typing = config.get_value("vimmode").unwrap_or(0) == 0 || insertmode || searchprefix.is_some();
+                    if typing && !value.contains(['\n', '\r', '\t']) {
+                        inputword.push_str(value);
+                        let prefix = searchprefix.map(String::from).unwrap_or_default();
+                        print_input_word(vstart, stdout, &format!("{}{}", prefix, inputword))?;
+                        continue;
+                    }
+                    data.status_message = match data.paste_text(&text, config) {
+                        Ok((rows, cols)) => format!("Pasted {} x {} cells.", rows, cols),
+                        Err(e) => e
+                    };
+                    let prefix = searchprefix.map(String::from).unwrap_or_default();
+                    vstart = redraw_in_place(config, data, &mut screen, vstart, &format!("{}{}", prefix, inputword), stdout)?;
+                    continue;
+                }
+                ioutils::Input::Resize => {
+                    // Lay out the sheet again for the new size, redrawing everything
+                    screen.invalidate();
+                    let prefix = searchprefix.map(String::from).unwrap_or_default();
+                    vstart = redraw_in_place(config, data, &mut screen, vstart, &format!("{}{}", prefix, inputword), stdout)?;
+                    continue;
+                }
+            };
+            let ink = inkey.code;
             if config.get_value("vimmode").unwrap_or(0) == 0 {
                 // NORMAL MODE KEYBINDS
                 match ink {
+                    crossterm::event::KeyCode::F(3) => {
+                        // Move to the next (or previous, with shift) search match
+                        data.search_next(inkey.modifiers.contains(crossterm::event::KeyModifiers::SHIFT));
+                    }
                     crossterm::event::KeyCode::Esc => {
-                        // Quit out of the command cycle
-                        return io::Result::Ok(());
+                        if data.has_range() {
+                            // Stop selecting the range
+                            data.clear_range();
+                        } else {
+                            // Quit out of the command cycle
+                            return io::Result::Ok(());
+                        }
+                    }
+                    crossterm::event::KeyCode::Up | crossterm::event::KeyCode::Left | crossterm::event::KeyCode::Down | crossterm::event::KeyCode::Right => {
+                        let delta = match ink {
+                            crossterm::event::KeyCode::Up => (-1, 0),
+                            crossterm::event::KeyCode::Left => (0, -1),
+                            crossterm::event::KeyCode::Down => (1, 0),
+                            _ => (0, 1)
+                        };
+                        if inkey.modifiers.contains(crossterm::event::KeyModifiers::SHIFT) {
+                            // Shift: select a range
+                            data.extend_range(delta);
+                        } else {
+                            data.clear_range();
+                            data.move_selected_coords(delta);
+                        }
                     }
-                    crossterm::event::KeyCode::Up => data.move_selected_coords((-1, 0)),
-                    crossterm::event::KeyCode::Left => data.move_selected_coords((0, -1)),
-                    crossterm::event::KeyCode::Down => data.move_selected_coords((1, 0)),
-                    crossterm::event::KeyCode::Right => data.move_selected_coords((0, 1)),
                     crossterm::event::KeyCode::Backspace => {
                         // Delete the last char in inputword if it exists; otherwise, clear the cell
                         if !inputword.is_empty() {
@@ -352,14 +827,14 @@ fn control_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::Shee
                             print_input_word(vstart, stdout, &inputword)?;
                             endinput = false;
                         } else {
-                            data.set_selected_cell_value(String::new(), &config); // Cleared; rerender
+                            data.clear_selected_cells(config); // Cleared; rerender
                         }
                     }
                     crossterm::event::KeyCode::Enter => {
                         // Enter the data if it exists, then move down
                         if !inputword.is_empty() {
                             // Already typed a word: enter it and move down
-                            data.set_selected_cell_value(inputword.clone(), &config);
+                            data.set_selected_cell_value(inputword.clone(), config);
                             data.move_selected_coords((1, 0));
                         } else {
                             // Did not type a word yet
@@ -378,6 +853,34 @@ fn control_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::Shee
                             }
                         }
                     }
+                    crossterm::event::KeyCode::Char(c) if inkey.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
+                        match c {
+                            'd' => {
+                                // Fill the selected range down
+                                data.fill_selection(&sheetdata::Fill::Down, config);
+                            }
+                            'r' => {
+                                // Fill the selected range right
+                                data.fill_selection(&sheetdata::Fill::Right, config);
+                            }
+                            'c' => {
+                                // Copy the selected cells
+                                data.yank_selection(None, selection_register_kind(data));
+                            }
+                            'x' => {
+                                // Cut the selected cells
+                                data.cut_selection(None, selection_register_kind(data), config);
+                            }
+                            'v' => {
+                                // Paste at the selected cell
+                                data.paste(None, false, 1, config);
+                            }
+                            _ => {
+                                // Irrelevant shortcut: do nothing
+                                endinput = false;
+                            }
+                        }
+                    }
                     crossterm::event::KeyCode::Char(c) => {
                         // Char c has been typed
                         inputword.push(c);
@@ -392,14 +895,53 @@ fn control_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::Shee
             } else {
                 // VIM MODE KEYBINDINGS
                 // TODO: impl all
+                if let Some(prefix) = searchprefix {
+                    // Typing a search pattern
+                    match ink {
+                        crossterm::event::KeyCode::Enter => {
+                            searchprefix = None;
+                            if let Err(e) = data.start_search(&inputword, prefix == '?') {
+                                data.status_message = e;
+                            }
+                            inputword.clear();
+                        }
+                        crossterm::event::KeyCode::Esc => {
+                            searchprefix = None;
+                            inputword.clear();
+                        }
+                        crossterm::event::KeyCode::Backspace if inputword.is_empty() => {
+                            searchprefix = None;
+                        }
+                        crossterm::event::KeyCode::Backspace => {
+                            inputword.pop();
+                            print_input_word(vstart, stdout, &format!("{}{}", prefix, inputword))?;
+                            endinput = false;
+                        }
+                        crossterm::event::KeyCode::Char(c) => {
+                            inputword.push(c);
+                            print_input_word(vstart, stdout, &format!("{}{}", prefix, inputword))?;
+                            endinput = false;
+                        }
+                        _ => {
+                            endinput = false;
+                        }
+                    }
+                    if endinput {
+                        break;
+                    }
+                    continue;
+                }
                 match ink {
                     crossterm::event::KeyCode::Esc | crossterm::event::KeyCode::Enter => {
                         if insertmode {
                             // Exit insert mode, saving changes to the cell if needed
-                            data.set_selected_cell_value(inputword.clone(), &config);
+                            data.set_selected_cell_value(inputword.clone(), config);
                             inputword.clear();
                             insertmode = false;
                             endinput = true;
+                        } else if data.has_range() {
+                            // Exit visual mode
+                            data.clear_range();
                         }
                     },
                     crossterm::event::KeyCode::Backspace => {
@@ -422,15 +964,86 @@ fn control_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::Shee
                             let real_repeat_times = cmp::max(1, repeat_times as isize);
                             // Normal mode command?
                             match c {
+                                _ if priorcapture == '"' => {
+                                    // Choose the register for the next yank, cut, or paste
+                                    if register::Registers::is_valid_name(c) {
+                                        register = Some(c);
+                                        priorcapture = ' ';
+                                        endinput = false;
+                                    } else {
+                                        data.status_message = format!("Invalid register \"{}\".", c);
+                                    }
+                                }
+                                '"' if priorcapture == ' ' => {
+                                    // Followed by the name of a register, so do not exit yet
+                                    priorcapture = c;
+                                    endinput = false;
+                                }
                                 ':' => {
                                     // Quit out of the command cycle
                                     return io::Result::Ok(());
                                 }
-                                'h' => data.move_selected_coords((0, -1 * real_repeat_times)),
+                                'y' if data.has_range() => {
+                                    // Yank the selected cells (or rows, in visual line mode)
+                                    data.yank_selection(register, selection_register_kind(data));
+                                }
+                                'y' if priorcapture == ' ' => {
+                                    // Yank: followed by a 'c', 'l', 'r', or 'y', so do not exit yet
+                                    priorcapture = c;
+                                    endinput = false;
+                                }
+                                'y' | 'r' if priorcapture == 'y' => {
+                                    // Yank rows
+                                    select_count(data, register::RegisterKind::Rows, real_repeat_times);
+                                    data.yank_selection(register, register::RegisterKind::Rows);
+                                }
+                                'c' if priorcapture == 'y' => {
+                                    // Yank columns
+                                    select_count(data, register::RegisterKind::Columns, real_repeat_times);
+                                    data.yank_selection(register, register::RegisterKind::Columns);
+                                }
+                                'l' if priorcapture == 'y' => {
+                                    // Yank cells to the right
+                                    select_count(data, register::RegisterKind::Cells, real_repeat_times);
+                                    data.yank_selection(register, register::RegisterKind::Cells);
+                                }
+                                'p' | 'P' => {
+                                    // Paste after (or before) the selected cell, count times
+                                    data.paste(register, c == 'P', real_repeat_times as usize, config);
+                                }
+                                '<' | '>' => {
+                                    // Narrow or widen the current column
+                                    let delta = if c == '<' { -real_repeat_times } else { real_repeat_times };
+                                    data.resize_column(data.selected().unwrap_or((0, 0)).1, delta, config);
+                                }
+                                'h' => data.move_selected_coords((0, -real_repeat_times)),
                                 'j' => data.move_selected_coords((real_repeat_times, 0)),
-                                'k' => data.move_selected_coords((-1 * real_repeat_times, 0)),
+                                'k' => data.move_selected_coords((-real_repeat_times, 0)),
                                 'l' => data.move_selected_coords((0, real_repeat_times)),
-                                'x' => data.set_selected_cell_value(String::new(), &config), // Cleared; rerender
+                                'x' => {
+                                    // Cut the cell (or cells to the right, or the selected cells)
+                                    select_count(data, register::RegisterKind::Cells, real_repeat_times);
+                                    data.cut_selection(register, register::RegisterKind::Cells, config);
+                                }
+                                'v' if inkey.modifiers.contains(crossterm::event::KeyModifiers::CONTROL) => {
+                                    // Visual block mode
+                                    toggle_range(data, sheet::SelectionMode::Block);
+                                }
+                                'v' => toggle_range(data, sheet::SelectionMode::Block), // Visual mode (cells are always a block)
+                                'V' => toggle_range(data, sheet::SelectionMode::Rows), // Visual line mode (whole rows)
+                                'd' if data.has_range() && priorcapture != 'd' && priorcapture != 'o' => {
+                                    // Cut the selected rows (visual line mode), or the selected cells
+                                    data.cut_selection(register, selection_register_kind(data), config);
+                                }
+                                '/' | '?' => {
+                                    // Start typing a search pattern
+                                    searchprefix = Some(c);
+                                    inputword.clear();
+                                    print_input_word(vstart, stdout, &c.to_string())?;
+                                    endinput = false;
+                                }
+                                'n' => data.search_next(false),
+                                'N' => data.search_next(true),
                                 'd' | 'o' if priorcapture != 'd' && priorcapture != 'o' => {
                                     // Delete or open: followed by a 'c', 'd', 'o', or 'r', so do not exit yet
                                     priorcapture = c;
@@ -449,40 +1062,46 @@ fn control_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::Shee
                                     }
                                 },
                                 'c' if priorcapture == 'd' => {
-                                    // Delete a column
-                                    for _i in 0..real_repeat_times {
-                                        data.delete_column(data.selected().unwrap_or((0, 0)).1, &config);
-                                    }
+                                    // Delete (cut) columns
+                                    select_count(data, register::RegisterKind::Columns, real_repeat_times);
+                                    data.cut_selection(register, register::RegisterKind::Columns, config);
                                 },
                                 'c' if priorcapture == 'o' => {
                                     // Insert a column left
+                                    data.begin_transaction();
                                     for _i in 0..real_repeat_times {
-                                        data.insert_column(data.selected().unwrap_or((0, 0)).1, &config);
+                                        data.insert_column(data.selected().unwrap_or((0, 0)).1, config);
                                     }
+                                    data.commit_transaction(config);
                                 },
                                 'C' if priorcapture == 'o' => {
                                     // Insert a column right
+                                    data.begin_transaction();
                                     for _i in 0..real_repeat_times {
-                                        data.insert_column(data.selected().unwrap_or((0, 0)).1 + 1, &config);
+                                        data.insert_column(data.selected().unwrap_or((0, 0)).1 + 1, config);
                                     }
+                                    data.commit_transaction(config);
                                 }
                                 'd' | 'r' if priorcapture == 'd' => {
-                                    // Delete a row
-                                    for _i in 0..real_repeat_times {
-                                        data.delete_row(data.selected().unwrap_or((0, 0)).0, &config);
-                                    }
+                                    // Delete (cut) rows
+                                    select_count(data, register::RegisterKind::Rows, real_repeat_times);
+                                    data.cut_selection(register, register::RegisterKind::Rows, config);
                                 },
                                 'o' | 'r' if priorcapture == 'o' => {
                                     // Insert a row left
+                                    data.begin_transaction();
                                     for _i in 0..real_repeat_times {
-                                        data.insert_row(data.selected().unwrap_or((0, 0)).0, &config);
+                                        data.insert_row(data.selected().unwrap_or((0, 0)).0, config);
                                     }
+                                    data.commit_transaction(config);
                                 },
                                 'O' | 'R' if priorcapture == 'o' => {
                                     // Insert a row right
+                                    data.begin_transaction();
                                     for _i in 0..real_repeat_times {
-                                        data.insert_row(data.selected().unwrap_or((0, 0)).0 + 1, &config);
+                                        data.insert_row(data.selected().unwrap_or((0, 0)).0 + 1, config);
                                     }
+                                    data.commit_transaction(config);
                                 },
                                 'c' | 'i' => {
                                     // Change the cell's value
@@ -500,6 +1119,15 @@ fn control_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::Shee
                                         data.undo();
                                     }
                                 },
+                                'g' if priorcapture == ' ' => {
+                                    // Followed by a '-' or '+', so do not exit yet
+                                    priorcapture = c;
+                                    endinput = false;
+                                },
+                                '-' | '+' if priorcapture == 'g' => {
+                                    // Go to the previous or next state of the undo history, across branches
+                                    data.travel_history(history::Travel::States(real_repeat_times as usize), c == '+');
+                                },
                                 'r' => {
                                     // TODO: impl, check this does not conflict with the 'r' ifs above
                                     for _i in 0..real_repeat_times {
@@ -529,6 +1157,8 @@ fn control_cycle(config: &mut configdata::ConfigData, data: &mut sheetdata::Shee
                 break;
             }
         }
+        // The input word was printed straight to the terminal, so redraw its line
+        screen.forget_line(vstart);
     }
 
     // Code after the above is unreachable, so no need to return here
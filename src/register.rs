@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+/// The name of the register used when none is given
+pub const UNNAMED: char = '"';
+
+/// What a register holds, which decides how it is pasted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegisterKind {
+    Cells, // A block of cells, pasted over the cells at the selected cell
+    Rows, // Whole rows, pasted as new rows
+    Columns // Whole columns, pasted as new columns
+}
+
+/// Yanked or cut cells
+#[derive(Clone, Debug, PartialEq)]
+pub struct Register {
+    pub cells: Vec<Vec<String>>, // Raw cell text (formulas are kept as formulas)
+    pub kind: RegisterKind,
+    pub origin: (usize, usize) // Where the top left cell came from, for moving relative formula references
+}
+
+impl Register {
+    /// Get the size of the contents as (rows, columns)
+    pub fn size(&self) -> (usize, usize) {
+        (self.cells.len(), self.cells.iter().map(|row| row.len()).max().unwrap_or(0))
+    }
+}
+
+/// Stores the registers by name (vim-style: the unnamed register `"` and the named registers `a` to `z`)
+#[derive(Clone, Debug, Default)]
+pub struct Registers {
+    registers: HashMap<char, Register>
+}
+
+impl Registers {
+    /// Whether a character names a register
+    pub fn is_valid_name(name: char) -> bool {
+        name == UNNAMED || name.is_ascii_lowercase()
+    }
+    /// Get a register (the unnamed register if None)
+    pub fn get(&self, name: Option<char>) -> Option<&Register> {
+        self.registers.get(&name.unwrap_or(UNNAMED))
+    }
+    /// Store into a register; like vim, the unnamed register always gets the latest contents too
+    pub fn set(&mut self, name: Option<char>, register: Register) {
+        if let Some(name) = name.filter(|&n| n != UNNAMED) {
+            self.registers.insert(name, register.clone());
+        }
+        self.registers.insert(UNNAMED, register);
+    }
+}
@@ -1,29 +1,158 @@
 use crate::sheetdata;
 use crate::configdata;
+use crate::csvformat;
+use crate::textwidth;
+use crate::screen::Screen;
 use crate::ioutils::{
-    printat, printstyl, clear, set_raw_mode, flush
+    printat, flush, LEFT_INPUT_WORD_BOUND, INPUT_WORD_WIDTH
 };
 use std::{ cmp, io };
-use crossterm::style::Stylize;
+use crossterm::{ terminal, style::Stylize };
 
-/// Format the inner contents of a cell
+/// Format the inner contents of a cell (exactly maxwidth terminal columns wide, for any Unicode text)
 fn fmt_string_padding(instr: &str, maxwidth: usize) -> String {
-    let mut fmtval: String = String::new();
-    for i in 0..maxwidth {
-        if i >= instr.len() {
-            fmtval.push(' ');
-        } else {
-            fmtval.push(instr.chars().nth(i).unwrap());
+    textwidth::fit(instr, maxwidth)
+}
+
+/// Format a count with thousands separators (ex. 1,300)
+fn fmt_count(n: usize) -> String {
+    let digits = n.to_string();
+    let mut res = String::new();
+    for (i, c) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            res.push(',');
         }
+        res.push(c);
+    }
+    res
+}
+
+/// Lines of the screen above the sheet rows (title, divider and column titles)
+const TOP_LINES: usize = 3;
+/// Lines of the screen below the sheet rows (divider, selected cell, prompt or status, command message)
+const BOTTOM_LINES: usize = 4;
+
+/// Get the terminal size as (columns, lines)
+fn terminal_size() -> (usize, usize) {
+    let (width, height) = terminal::size().unwrap_or((80, 24));
+    (width.into(), height.into())
+}
+
+/// Get a view size from the config, or None if it is 0 (or unset), in which case the view fits the terminal
+fn view_config(config: &configdata::ConfigData, key: &str) -> Option<usize> {
+    config.get_value(key).filter(|&v| v > 0).map(|v| v as usize)
+}
+
+/// Get the width taken by the row titles, before the first column
+fn row_title_width(config: &configdata::ConfigData) -> usize {
+    let maxcellwidth: usize = config.get_value("maxcellwidth").unwrap_or(5).try_into().unwrap_or(5);
+    maxcellwidth + 2
+}
+
+/// Get the rows shown on screen: the frozen rows, then the rows around the selected row
+/// Rows hidden by filters are skipped (unless selected or frozen)
+pub fn shown_rows(config: &configdata::ConfigData, data: &sheetdata::SheetData) -> Vec<usize> {
+    let viewheight = view_config(config, "viewcellsheight")
+        .unwrap_or_else(|| terminal_size().1.saturating_sub(TOP_LINES + BOTTOM_LINES));
+    let frozenrows = cmp::min(cmp::min(data.frozen().0, data.bounds().0), viewheight);
+    let scrollheight = viewheight - frozenrows;
+    let selectedrow = data.selected().unwrap_or((0, 0)).0;
+    let is_shown = |row: usize| row == selectedrow || !data.is_row_hidden(row);
+    // Start up to half the view above the selected row (or the first row after the frozen ones), then fill the view downwards
+    let center = cmp::max(selectedrow, frozenrows);
+    let vtop = (frozenrows..center).rev().filter(|&r| is_shown(r)).take(scrollheight / 2).last().unwrap_or(center);
+    let mut rows: Vec<usize> = (0..frozenrows).collect();
+    rows.extend((vtop..data.bounds().0).filter(|&r| is_shown(r)).take(scrollheight));
+    rows
+}
+
+/// Get the columns shown on screen: the frozen columns, then the columns around the selected column
+pub fn shown_columns(config: &configdata::ConfigData, data: &sheetdata::SheetData) -> Vec<usize> {
+    let center = data.selected().unwrap_or((0, 0)).1;
+    if let Some(viewwidth) = view_config(config, "viewcellswidth") {
+        let frozencols = cmp::min(cmp::min(data.frozen().1, data.bounds().1), viewwidth);
+        let scrollwidth = viewwidth - frozencols;
+        let center = cmp::max(center, frozencols);
+        let vleft = cmp::max(center.saturating_sub(scrollwidth / 2), frozencols);
+        let vright = cmp::min(vleft + scrollwidth, data.bounds().1); // Non-inclusive bound
+        return (0..frozencols).chain(vleft..vright).collect();
+    }
+    // Fit as many columns as the terminal has room for (each takes its width plus 2)
+    let space = terminal_size().0.saturating_sub(row_title_width(config));
+    let cellwidth = |col: usize| data.column_width(col, config) + 2;
+    let frozencols = cmp::min(data.frozen().1, data.bounds().1);
+    let mut used = 0;
+    let mut cols = Vec::new();
+    for col in 0..frozencols {
+        if used + cellwidth(col) > space {
+            return cols;
+        }
+        used += cellwidth(col);
+        cols.push(col);
+    }
+    let center = cmp::max(center, frozencols);
+    if center >= data.bounds().1 {
+        return cols;
+    }
+    // The selected column is always shown; take columns to its left for up to half the room left, then fill to the right (then the left)
+    let scrollspace = space - used;
+    let mut scrollused = cellwidth(center);
+    let (mut vleft, mut vright) = (center, center + 1); // Non-inclusive right bound
+    while vleft > frozencols && scrollused + cellwidth(vleft - 1) <= scrollspace / 2 {
+        vleft -= 1;
+        scrollused += cellwidth(vleft);
+    }
+    while vright < data.bounds().1 && scrollused + cellwidth(vright) <= scrollspace {
+        scrollused += cellwidth(vright);
+        vright += 1;
+    }
+    while vleft > frozencols && scrollused + cellwidth(vleft - 1) <= scrollspace {
+        vleft -= 1;
+        scrollused += cellwidth(vleft);
+    }
+    cols.extend(vleft..vright);
+    cols
+}
+
+/// Get the shown columns with their screen x coordinates and display widths, as (column, x, width)
+/// Each cell takes its width plus 2 (for the brackets around the selected cell), after the row titles
+pub fn column_layout(config: &configdata::ConfigData, data: &sheetdata::SheetData) -> Vec<(usize, u16, usize)> {
+    let mut x = row_title_width(config);
+    let mut res = Vec::new();
+    for col in shown_columns(config, data) {
+        let width = data.column_width(col, config);
+        res.push((col, x.try_into().unwrap_or(u16::MAX), width));
+        x += width + 2;
+    }
+    res
+}
+
+/// Get the smallest terminal size (columns, lines) that fits the selected cell and one row, or None if the terminal is big enough
+fn needed_terminal_size(config: &configdata::ConfigData, data: &sheetdata::SheetData) -> Option<(usize, usize)> {
+    let (termwidth, termheight) = terminal_size();
+    let selectedcol = data.selected().unwrap_or((0, 0)).1;
+    let minwidth = row_title_width(config) + data.column_width(selectedcol, config) + 2;
+    let minheight = TOP_LINES + 1 + BOTTOM_LINES;
+    if termwidth < minwidth || termheight < minheight {
+        Some((minwidth, minheight))
+    } else {
+        None
     }
-    fmtval
 }
 
 /// Render the sheet
-pub fn render(config: &mut configdata::ConfigData, data: &sheetdata::SheetData, stdout: &mut io::Stdout) -> io::Result<()> {
+pub fn render(config: &configdata::ConfigData, data: &sheetdata::SheetData, screen: &mut Screen, stdout: &mut io::Stdout) -> io::Result<()> {
     // Prep
-    set_raw_mode(true)?;
-    clear(stdout)?;
+    screen.clear();
+
+    // Only show a warning if not even the selected cell fits in the terminal
+    if let Some((minwidth, minheight)) = needed_terminal_size(config, data) {
+        screen.print(0, 0, "Terminal too small.".to_string().dark_red());
+        screen.print(0, 1, format!("Resize it to at least {} x {}.", minwidth, minheight).dark_red());
+        screen.present(stdout)?;
+        flush(stdout)?;
+        return io::Result::Ok(());
+    }
 
     // Render sheet title and info
     // TODO: print filename only (not full path; search backwards by / or \, and provide the path in a separate command) ?
@@ -35,85 +164,89 @@ pub fn render(config: &mut configdata::ConfigData, data: &sheetdata::SheetData,
             filenameonly.insert(0, c);
         }
     }
-    printat(0, 0, &format!("{}{} ({} x {})", if data.unsaved { "*" } else { "" }, filenameonly, data.bounds().0, data.bounds().1), stdout)?;
-    printat(0, 1, "----", stdout)?;
+    let delimiterinfo = if data.delimiter == ',' {
+        String::new()
+    } else {
+        format!(" [{}]", csvformat::delimiter_name(data.delimiter))
+    };
+    let filterinfo = if data.filters().is_empty() {
+        String::new()
+    } else {
+        format!(" (filtered: {} of {} rows)", fmt_count(data.shown_row_count()), fmt_count(data.bounds().0))
+    };
+    screen.print(0, 0, format!("{}{} ({} x {}){}{}", if data.unsaved { "*" } else { "" }, filenameonly, data.bounds().0, data.bounds().1, delimiterinfo, filterinfo).reset());
+    screen.print(0, 1, "----".to_string().reset());
 
     // Determine sheet bounds
-    let viewwidth: usize = config.get_value("viewcellswidth").unwrap_or(10).try_into().unwrap_or(10);
-    let viewheight: usize = config.get_value("viewcellsheight").unwrap_or(10).try_into().unwrap_or(10);
     let selectedcoords = data.selected().unwrap_or((0, 0));
-    let vleft: usize = cmp::max(selectedcoords.1.saturating_sub(viewwidth / 2), 0);
-    let vright: usize = cmp::min(vleft + viewwidth, data.bounds().1); // Non-inclusive bound
-    let vtop: usize = cmp::max(selectedcoords.0.saturating_sub(viewheight / 2), 0);
-    let vbottom: usize = cmp::min(vtop + viewheight, data.bounds().0);
-
-    // Get config info
-    let maxcellwidth: u16 = config.get_value("maxcellwidth").unwrap_or(5).try_into().unwrap_or(5);
-
-    // Render debug info
-    //printstyl(70, 1, format!("dbg: len={}, curr={}", data.dbg_get_history_info().0, data.dbg_get_history_info().1).dark_cyan(), stdout)?;
+    let (frozenrows, frozencols) = data.frozen();
+    let columns = column_layout(config, data);
+    let rows = shown_rows(config, data);
+    let vheight = rows.len() as u16;
 
     // Render row and column titles
     // TODO: more colors
-    // TODO: display a warning/error/instructions if the terminal size is too small to fit the whole screen
-    let mut xpos: u16 = 1;
-    for col in vleft..vright {
+    for &(col, xpos, _) in &columns {
         if selectedcoords.1 == col {
-            printstyl((maxcellwidth + 2) * xpos, 2, format!("{}", col).dark_cyan(), stdout)?;
+            screen.print(xpos, 2, format!("{}", col).dark_cyan());
+        } else if col < frozencols {
+            screen.print(xpos, 2, format!("{}", col).dark_blue());
         } else {
-            printstyl((maxcellwidth + 2) * xpos, 2, format!("{}", col).dark_grey(), stdout)?;
+            screen.print(xpos, 2, format!("{}", col).dark_grey());
         }
-        xpos += 1;
     }
-    let mut ypos: u16 = 1;
-    for row in vtop..vbottom {
+    for (ypos, &row) in (1_u16..).zip(rows.iter()) {
         if selectedcoords.0 == row {
-            printstyl(0, ypos + 2, format!("{}", row).dark_cyan(), stdout)?;
+            screen.print(0, ypos + 2, format!("{}", row).dark_cyan());
+        } else if row < frozenrows {
+            screen.print(0, ypos + 2, format!("{}", row).dark_blue());
         } else {
-            printstyl(0, ypos + 2, format!("{}", row).dark_grey(), stdout)?;
+            screen.print(0, ypos + 2, format!("{}", row).dark_grey());
         }
-        ypos += 1;
     }
 
     // Render cells
-    for row in vtop..vbottom {
-        for col in vleft..vright {
+    for (ypos, &row) in (3_u16..).zip(rows.iter()) {
+        for &(col, xpos, width) in &columns {
             // Do this
-            let cellval = data.cell((row, col)).unwrap_or("");
-            let fmtval = fmt_string_padding(cellval, maxcellwidth.into());
+            let cellval = data.display_value((row, col)).unwrap_or_default();
+            let fmtval = fmt_string_padding(&cellval, width);
             // Render based on user selection
             if data.selected().is_some() && (row, col) == data.selected().unwrap() {
-                printstyl(
-                    ((maxcellwidth + 2) as usize * (col + 1 - vleft)).try_into().unwrap_or(0),
-                    ((row + 3) - vtop).try_into().unwrap_or(0),
-                    format!("[{}]", fmtval).cyan(),
-                    stdout
-                )?;
+                screen.print(xpos, ypos, format!("[{}]", fmtval).cyan());
+            } else if data.in_selection((row, col)) {
+                screen.print(xpos, ypos, format!(" {} ", fmtval).black().on_grey());
+            } else if data.is_search_match((row, col)) {
+                screen.print(xpos, ypos, format!(" {} ", fmtval).black().on_dark_yellow());
             } else {
-                printstyl(
-                    ((maxcellwidth + 2) as usize * (col + 1 - vleft)).try_into().unwrap_or(0),
-                    ((row + 3) - vtop).try_into().unwrap_or(0),
-                    format!(" {} ", fmtval).reset(),
-                    stdout
-                )?;
+                screen.print(xpos, ypos, format!(" {} ", fmtval).reset());
             }
         }
     }
 
-    printat(0, (vbottom - vtop + 3) as u16, "----", stdout)?;
+    screen.print(0, vheight + 3, "----".to_string().reset());
 
     if data.selected().is_some() && data.selected_cell_value().is_some() {
         let selectedstr = format!("({}, {}):", data.selected().unwrap().0, data.selected().unwrap().1);
-        printat(0, (vbottom - vtop + 4) as u16, &selectedstr, stdout)?;
-        printat(15, (vbottom - vtop + 4) as u16, &format!("{}", data.selected_cell_value().unwrap()), stdout)?;
+        screen.print(0, vheight + 4, selectedstr.reset());
+        screen.print(LEFT_INPUT_WORD_BOUND, vheight + 4, textwidth::fit(data.selected_cell_value().unwrap(), INPUT_WORD_WIDTH.into()).reset());
     } else {
-        printat(0, (vbottom - vtop + 4) as u16, "no cell selected", stdout)?;
+        screen.print(0, vheight + 4, "no cell selected".to_string().reset());
+    }
+    // Print the status message (ex. search results)
+    if !data.status_message.is_empty() {
+        screen.print(0, vheight + 5, data.status_message.clone().dark_yellow());
+    } else if let Some((topleft, bottomright)) = data.selection_bounds().filter(|_| data.has_range()) {
+        // Otherwise, print the size of the selected range
+        let rangestr = format!("{} x {} selected", bottomright.0 - topleft.0 + 1, bottomright.1 - topleft.1 + 1);
+        screen.print(0, vheight + 5, rangestr.dark_grey());
     }
-    printat(0, 2, "", stdout)?;
 
     // TODO: print bottom message (for rerendering after commands rather than temporary command writing/overwriting)
 
-    // Flush the buffer to finish
+    // Write what changed since the last frame, then flush the buffer to finish
+    screen.present(stdout)?;
+    printat(0, 2, "", stdout)?;
     flush(stdout)?;
 
     // Was successful
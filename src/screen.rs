@@ -0,0 +1,130 @@
+use std::io;
+use crossterm::{
+    queue, cursor, terminal, style::{ self, ContentStyle, StyledContent }
+};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// One terminal column of the screen
+#[derive(Clone, Debug, PartialEq)]
+struct ScreenCell {
+    text: String, // A grapheme cluster, or empty if covered by the wide character before it
+    style: ContentStyle
+}
+
+impl ScreenCell {
+    fn blank() -> ScreenCell {
+        ScreenCell { text: " ".to_string(), style: ContentStyle::default() }
+    }
+}
+
+/// A double-buffered model of the terminal screen
+/// Frames are drawn into the back buffer, then only the cells that changed since the last frame are written to the terminal
+pub struct Screen {
+    back: Vec<Vec<ScreenCell>>, // The frame being drawn
+    front: Vec<Option<Vec<ScreenCell>>> // What each line of the terminal shows (None if unknown, so it is fully redrawn)
+}
+
+impl Screen {
+    /// Create a screen the size of the terminal, which fully redraws on the first frame
+    pub fn new() -> Screen {
+        let (width, height) = terminal::size().unwrap_or((80, 24));
+        Screen {
+            back: vec![vec![ScreenCell::blank(); width.into()]; height.into()],
+            front: vec![None; height.into()]
+        }
+    }
+    /// Start a new frame (blank)
+    pub fn clear(&mut self) {
+        for line in &mut self.back {
+            line.fill(ScreenCell::blank());
+        }
+    }
+    /// Draw stylized text at a coordinate (anything past the edge of the screen is cut off)
+    pub fn print(&mut self, x: u16, y: u16, contents: StyledContent<String>) {
+        let Some(line) = self.back.get_mut(y as usize) else {
+            return;
+        };
+        let mut x = x as usize;
+        for grapheme in contents.content().graphemes(true) {
+            let width = grapheme.width();
+            if width == 0 {
+                continue; // Control characters
+            }
+            if x + width > line.len() {
+                break;
+            }
+            // Blank out the rest of any wide character being partly overwritten
+            let mut start = x;
+            while start > 0 && line[start].text.is_empty() {
+                start -= 1;
+                line[start] = ScreenCell::blank();
+            }
+            let mut end = x + width;
+            while end < line.len() && line[end].text.is_empty() {
+                line[end] = ScreenCell::blank();
+                end += 1;
+            }
+            line[x] = ScreenCell { text: grapheme.to_string(), style: *contents.style() };
+            for covered in &mut line[x + 1..x + width] {
+                *covered = ScreenCell { text: String::new(), style: *contents.style() };
+            }
+            x += width;
+        }
+    }
+    /// Forget what a line of the terminal shows (after printing to it directly), so it is redrawn on the next frame
+    pub fn forget_line(&mut self, y: u16) {
+        if let Some(line) = self.front.get_mut(y as usize) {
+            *line = None;
+        }
+    }
+    /// Fit the screen to the size of the terminal (after a resize) and forget what it shows, so it is fully redrawn on the next frame
+    pub fn invalidate(&mut self) {
+        *self = Screen::new();
+    }
+    /// Write the changes of the frame to the terminal (queued; flush the stdout to finish)
+    pub fn present(&mut self, stdout: &mut io::Stdout) -> io::Result<()> {
+        for y in 0..self.back.len() {
+            let line = &self.back[y];
+            let front = self.front[y].as_ref();
+            let mut x = 0;
+            while x < line.len() {
+                if front.is_some_and(|f| f[x] == line[x]) {
+                    x += 1;
+                    continue;
+                }
+                // Start a run of changed cells (from the start of a wide character)
+                let mut start = x;
+                while start > 0 && line[start].text.is_empty() {
+                    start -= 1;
+                }
+                let mut end = x + 1;
+                while end < line.len() && (front.is_none_or(|f| f[end] != line[end]) || line[end].text.is_empty()) {
+                    end += 1;
+                }
+                queue!(stdout, cursor::MoveTo(start as u16, y as u16))?;
+                // Write the run, joining cells of the same style
+                let mut text = String::new();
+                let mut textstyle = line[start].style;
+                for cell in &line[start..end] {
+                    if cell.style != textstyle {
+                        queue!(stdout, style::PrintStyledContent(StyledContent::new(textstyle, text.clone())))?;
+                        text.clear();
+                        textstyle = cell.style;
+                    }
+                    text.push_str(&cell.text);
+                }
+                queue!(stdout, style::PrintStyledContent(StyledContent::new(textstyle, text)))?;
+                x = end;
+            }
+            self.front[y] = Some(line.clone());
+        }
+        io::Result::Ok(())
+    }
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
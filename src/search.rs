@@ -0,0 +1,227 @@
+use regex::{ Regex, RegexBuilder };
+
+/// How a search pattern matches cell values
+#[derive(Clone, Debug)]
+pub enum Matcher {
+    Plain(String), // Contains the text
+    CaseInsensitive(String), // Contains the text, ignoring case (stored lowercase)
+    Regex(Regex) // Matches the regular expression
+}
+
+impl Matcher {
+    /// Parse a search pattern, optionally followed by `/` and flags: `i` (ignore case) and `r` (regex)
+    /// (ex. `foo`, `foo/i`, `^fo+$/r`, `^fo+$/ri`; use `\/` for a literal `/` before text that looks like flags)
+    pub fn parse(pattern: &str) -> Result<Matcher, String> {
+        let (text, flags) = split_flags(pattern);
+        if text.is_empty() {
+            return Err("Empty search pattern.".to_string());
+        }
+        let ignore_case = flags.contains('i');
+        if flags.contains('r') {
+            RegexBuilder::new(&text)
+                .case_insensitive(ignore_case)
+                .build()
+                .map(Matcher::Regex)
+                .map_err(|e| format!("Invalid regex: {}", e))
+        } else if ignore_case {
+            Ok(Matcher::CaseInsensitive(text.to_lowercase()))
+        } else {
+            Ok(Matcher::Plain(text))
+        }
+    }
+    /// Whether a cell value matches
+    pub fn is_match(&self, value: &str) -> bool {
+        match self {
+            Matcher::Plain(text) => value.contains(text.as_str()),
+            Matcher::CaseInsensitive(text) => value.to_lowercase().contains(text.as_str()),
+            Matcher::Regex(re) => re.is_match(value)
+        }
+    }
+}
+
+/// Split a pattern into its text (with `\/` unescaped) and its flags (empty if none)
+pub fn split_flags(pattern: &str) -> (String, String) {
+    // Find the last unescaped '/'
+    let mut last_slash: Option<usize> = None;
+    let mut escaped = false;
+    for (i, c) in pattern.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '/' {
+            last_slash = Some(i);
+        }
+    }
+    let (text, flags) = match last_slash {
+        Some(i) if pattern[i + 1..].chars().all(|c| c == 'i' || c == 'r') => (&pattern[..i], &pattern[i + 1..]),
+        _ => (pattern, "")
+    };
+    (text.replace("\\/", "/"), flags.to_string())
+}
+
+/// An active search, for moving between matches
+#[derive(Clone, Debug)]
+pub struct Search {
+    pub pattern: String,
+    pub matcher: Matcher,
+    pub backward: bool // Searching upwards (started with '?')
+}
+
+/// The cells a replacement applies to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplaceScope {
+    Sheet,
+    CurrentRow,
+    CurrentColumn,
+    Rows(usize, usize) // Inclusive
+}
+
+/// A find-and-replace operation on cell values
+#[derive(Clone, Debug)]
+pub struct Substitution {
+    pub regex: Regex,
+    pub replacement: String, // In regex syntax ($1, ${name}, and $$ for a literal $)
+    pub global: bool // Replace every occurrence in a cell, not just the first
+}
+
+impl Substitution {
+    /// Create a plain text substitution (every occurrence, no special characters)
+    pub fn plain(find: &str, replacement: &str) -> Result<Substitution, String> {
+        if find.is_empty() {
+            return Err("Empty search pattern.".to_string());
+        }
+        Ok(Substitution {
+            regex: Regex::new(&regex::escape(find)).map_err(|e| e.to_string())?,
+            replacement: replacement.replace('$', "$$"),
+            global: true
+        })
+    }
+    /// Parse a vim-style substitute command: `[range]s/pattern/replacement/[flags]`, or None if it is not one
+    /// Range: none or `.` (current row), `%` (whole sheet), `c` (current column), or `{start},{end}` (rows)
+    /// Flags: `g` (every occurrence in a cell) and `i` (ignore case); backreferences can be `\1` or `$1`
+    pub fn parse_vim(line: &str) -> Option<Result<(ReplaceScope, Substitution), String>> {
+        // A range never contains an 's', so the first one is the command
+        let s_index = line.find('s')?;
+        if !line[s_index + 1..].starts_with('/') {
+            return None;
+        }
+        let scope = match &line[..s_index] {
+            "" | "." => ReplaceScope::CurrentRow,
+            "%" => ReplaceScope::Sheet,
+            "c" => ReplaceScope::CurrentColumn,
+            range => {
+                let (start, end) = range.split_once(',')?;
+                ReplaceScope::Rows(start.trim().parse().ok()?, end.trim().parse().ok()?)
+            }
+        };
+        // Split the rest on unescaped '/' into the pattern, replacement, and flags
+        let mut parts: Vec<String> = vec![String::new()];
+        let mut chars = line[s_index + 2..].chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\\' && chars.peek() == Some(&'/') {
+                chars.next();
+                parts.last_mut().unwrap().push('/');
+            } else if c == '/' && parts.len() < 3 {
+                parts.push(String::new());
+            } else {
+                parts.last_mut().unwrap().push(c);
+            }
+        }
+        Some(Self::from_vim_parts(&parts).map(|substitution| (scope, substitution)))
+    }
+    /// Build a substitution from the pattern, replacement, and flags of a vim-style substitute command
+    fn from_vim_parts(parts: &[String]) -> Result<Substitution, String> {
+        let pattern = &parts[0];
+        if pattern.is_empty() {
+            return Err("Empty search pattern.".to_string());
+        }
+        let replacement = parts.get(1).cloned().unwrap_or_default();
+        let flags = parts.get(2).cloned().unwrap_or_default();
+        if let Some(c) = flags.chars().find(|c| *c != 'g' && *c != 'i') {
+            return Err(format!("Unknown substitute flag \"{}\".", c));
+        }
+        let regex = RegexBuilder::new(pattern)
+            .case_insensitive(flags.contains('i'))
+            .build()
+            .map_err(|e| format!("Invalid regex: {}", e))?;
+        Ok(Substitution {
+            regex,
+            replacement: convert_backreferences(&replacement),
+            global: flags.contains('g')
+        })
+    }
+    /// Apply to a value, returning the new value if anything was replaced
+    pub fn apply(&self, value: &str) -> Option<String> {
+        if !self.regex.is_match(value) {
+            return None;
+        }
+        let res = if self.global {
+            self.regex.replace_all(value, self.replacement.as_str())
+        } else {
+            self.regex.replace(value, self.replacement.as_str())
+        };
+        if res == value {
+            None
+        } else {
+            Some(res.into_owned())
+        }
+    }
+}
+
+/// Convert vim-style backreferences (`\1`) to regex syntax (`${1}`), keeping `$1` as is
+fn convert_backreferences(replacement: &str) -> String {
+    let mut res = String::new();
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some(d) if d.is_ascii_digit() => res.push_str(&format!("${{{}}}", d)),
+                Some('$') => res.push_str("$$"),
+                Some(other) => res.push(other),
+                None => res.push('\\')
+            }
+        } else {
+            res.push(c);
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope(line: &str) -> Option<ReplaceScope> {
+        Substitution::parse_vim(line).map(|parsed| parsed.unwrap().0)
+    }
+
+    #[test]
+    fn parses_vim_ranges() {
+        assert_eq!(scope("s/a/b/"), Some(ReplaceScope::CurrentRow));
+        assert_eq!(scope(".s/a/b/"), Some(ReplaceScope::CurrentRow));
+        assert_eq!(scope("%s/a/b/g"), Some(ReplaceScope::Sheet));
+        assert_eq!(scope("cs/a/b/"), Some(ReplaceScope::CurrentColumn));
+        assert_eq!(scope("2, 5s/a/b/"), Some(ReplaceScope::Rows(2, 5)));
+    }
+
+    #[test]
+    fn ignores_other_commands() {
+        assert!(Substitution::parse_vim("save data/s/x.csv").is_none());
+        assert!(Substitution::parse_vim("open docs/a.csv").is_none());
+        assert!(Substitution::parse_vim("replace s/ x").is_none());
+        assert!(Substitution::parse_vim("%x/a/b/").is_none());
+        assert!(Substitution::parse_vim("1,s/a/b/").is_none());
+    }
+
+    #[test]
+    fn substitutes() {
+        let (_, substitution) = Substitution::parse_vim("%s/(\\w+)@a\\.com/\\1@b.org/").unwrap().unwrap();
+        assert_eq!(substitution.apply("me@a.com, you@a.com").as_deref(), Some("me@b.org, you@a.com"));
+        let (_, substitution) = Substitution::parse_vim("s/A\\/B/$$/gi").unwrap().unwrap();
+        assert_eq!(substitution.apply("a/b A/B").as_deref(), Some("$ $"));
+        assert_eq!(substitution.apply("ab"), None);
+        assert!(Substitution::parse_vim("s/a/b/x").unwrap().is_err());
+        assert!(Substitution::parse_vim("s//b/").unwrap().is_err());
+    }
+}
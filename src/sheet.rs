@@ -1,9 +1,48 @@
-use std::cmp;
+use std::{ cmp, cmp::Ordering, collections::HashMap, mem, ops::Range };
+use crate::csvformat;
+use crate::formula::{ self, CellError, CellLookup, Expr, Precedent, Shift, Value };
+use crate::depgraph::{ DependencyGraph, RecalcOrder };
+use crate::sortkey::SortKey;
+use crate::textwidth;
+use crate::history::Operation;
 
 /// Stores the data for the sheet's cells
 pub struct Sheet {
     sheet: Vec<Vec<String>>,
-    pub selected: Option<(usize, usize)> // (y, x)
+    formulas: HashMap<(usize, usize), Expr>, // Parsed formula cells
+    values: HashMap<(usize, usize), Value>, // Computed values of formula cells
+    graph: DependencyGraph, // What each formula cell reads
+    hidden: Vec<bool>, // Rows hidden by filters (rows past the end are shown)
+    widths: Vec<Option<usize>>, // Display widths of columns (None, or columns past the end, use the default width)
+    pub selected: Option<(usize, usize)>, // (y, x)
+    pub anchor: Option<(usize, usize)>, // The other corner of the selected range, if selecting one
+    pub selection_mode: SelectionMode
+}
+
+/// How a selected range extends from its anchor to the selected cell
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SelectionMode {
+    Block, // The rectangle between the anchor and the selected cell
+    Rows // Whole rows between the anchor and the selected cell
+}
+
+/// Provides cell values to formulas, using the already computed values of formula cells
+struct SheetLookup<'a> {
+    sheet: &'a Sheet
+}
+
+impl CellLookup for SheetLookup<'_> {
+    fn value(&mut self, coords: (usize, usize)) -> Option<Value> {
+        let text = self.sheet.cell(coords)?;
+        if formula::is_formula(text) {
+            Some(self.sheet.values.get(&coords).cloned().unwrap_or(Value::Empty))
+        } else {
+            Some(Value::from_literal(text))
+        }
+    }
+    fn bounds(&self) -> (usize, usize) {
+        self.sheet.bounds()
+    }
 }
 
 impl Sheet {
@@ -11,81 +50,174 @@ impl Sheet {
     pub fn new() -> Sheet {
         Sheet {
             sheet: vec![vec!["".to_string(); 16]; 16],
-            selected: Some((0, 0))
+            formulas: HashMap::new(),
+            values: HashMap::new(),
+            graph: DependencyGraph::default(),
+            hidden: Vec::new(),
+            widths: Vec::new(),
+            selected: Some((0, 0)),
+            anchor: None,
+            selection_mode: SelectionMode::Block
         }
     }
     /// Clear the sheet
     pub fn clear(&mut self) {
         self.sheet.clear();
+        self.formulas.clear();
+        self.values.clear();
+        self.graph.clear();
+        self.hidden.clear();
+        self.widths.clear();
         self.selected = None;
+        self.anchor = None;
     }
-    /// Load the sheet from a string and return whether successful
-    pub fn load_string(&mut self, newstring: String) -> bool {
-        // Update the sheet by parsing the string
-        // TODO: comma/quote handling
-        self.clear();
-        let mut bound_width: usize = 0;
-        for resline in newstring.split('\n') {
-            if resline.trim().is_empty() {
-                continue;
-            }
-            self.sheet.push(Vec::new());
-            let sheetn = self.sheet.len();
-            let mut n: usize = 0;
-            for resword in resline.split(',') {
-                self.sheet[sheetn - 1].push(resword.trim().to_string());
-                n += 1;
+    /// Recompute the values of all formula cells (after the sheet was loaded)
+    fn recalculate(&mut self) {
+        self.formulas.clear();
+        self.values.clear();
+        self.graph.clear();
+        for row in 0..self.sheet.len() {
+            for col in 0..self.sheet[row].len() {
+                if formula::is_formula(&self.sheet[row][col]) {
+                    self.update_formula((row, col));
+                }
             }
-            bound_width = cmp::max(bound_width, n);
-            // Fill in extra lines
-            while n < bound_width {
-                self.sheet[sheetn - 1].push(String::new());
-                n += 1;
+        }
+        self.recalculate_cells(&self.graph.formula_cells());
+    }
+    /// Rewrite the references of every formula for a row or column about to be inserted or deleted
+    /// Returns the texts the changed cells had, to undo it (the coordinates are from before the change), along with
+    /// those of deletions that inserting the row or column back would not restore (ex. `A3:$A$10` deleting row 2)
+    fn shift_formulas(&mut self, shift: Shift) -> Vec<((usize, usize), String)> {
+        let inverse = match shift {
+            Shift::DeleteRow(at) => Some(Shift::InsertRow(at)),
+            Shift::DeleteColumn(at) => Some(Shift::InsertColumn(at)),
+            _ => None
+        };
+        let mut changed = Vec::new();
+        for (row, line) in self.sheet.iter_mut().enumerate() {
+            for (col, text) in line.iter_mut().enumerate() {
+                if let Some(adjusted) = formula::adjust_references(text, shift) {
+                    changed.push(((row, col), mem::replace(text, adjusted)));
+                } else if inverse.is_some_and(|inverse| formula::adjust_references(text, inverse).is_some()) {
+                    changed.push(((row, col), text.clone()));
+                }
             }
         }
-        // TODO: test rectangularization
+        changed
+    }
+    /// Move the formula cells to where their rows or columns moved (or remove them, for None), then recompute
+    /// the formulas rewritten by shift_formulas (at their coordinates from before the change), the inserted cells,
+    /// and the formulas reading an area whose cells moved (rectangles of rows, columns)
+    fn move_formulas(
+        &mut self,
+        moved: impl Fn((usize, usize)) -> Option<(usize, usize)>,
+        rewritten: &[((usize, usize), String)],
+        inserted: &[(usize, usize)],
+        areas: &[(Range<usize>, Range<usize>)]
+    ) {
+        self.formulas = mem::take(&mut self.formulas).into_iter().filter_map(|(coords, expr)| Some((moved(coords)?, expr))).collect();
+        self.values = mem::take(&mut self.values).into_iter().filter_map(|(coords, value)| Some((moved(coords)?, value))).collect();
+        self.graph.remap(&moved);
+        let mut changed: Vec<(usize, usize)> = rewritten.iter().filter_map(|&(coords, _)| moved(coords)).collect();
+        changed.extend(inserted);
+        for &coords in &changed {
+            self.update_formula(coords);
+        }
+        for (rows, cols) in areas {
+            changed.extend(self.graph.readers(rows.clone(), cols.clone()));
+        }
+        self.recalculate_cells(&changed);
+    }
+    /// Parse the formula of a cell (if any) and update what it reads
+    fn update_formula(&mut self, coords: (usize, usize)) {
+        let text = self.cell(coords).unwrap_or("");
+        if formula::is_formula(text) {
+            let expr = formula::parse(text).unwrap_or_else(Expr::Error);
+            let mut precedents: Vec<Precedent> = Vec::new();
+            expr.precedents(&mut precedents);
+            self.graph.set_precedents(coords, precedents);
+            self.formulas.insert(coords, expr);
+        } else {
+            self.graph.remove(coords);
+            self.formulas.remove(&coords);
+            self.values.remove(&coords);
+        }
+    }
+    /// Recompute the formula cells depending (transitively) on the changed cells
+    fn recalculate_cells(&mut self, changed: &[(usize, usize)]) {
+        let RecalcOrder { order, circular } = self.graph.recalc_order(changed);
+        for coords in circular {
+            self.values.insert(coords, Value::Error(CellError::Circular));
+        }
+        for coords in order {
+            let Some(expr) = self.formulas.get(&coords) else {
+                continue;
+            };
+            let value = formula::evaluate(expr, &mut SheetLookup { sheet: self });
+            self.values.insert(coords, value);
+        }
+    }
+    /// Load the sheet from parsed rows (see csvformat::parse), made rectangular
+    pub fn load_rows(&mut self, rows: Vec<Vec<String>>) {
+        self.clear();
+        self.sheet = rows;
         // Make the sheet rectangular, if not already, given the longest row
+        let bound_width: usize = self.sheet.iter().map(|line| line.len()).max().unwrap_or(0);
         for line in &mut self.sheet {
             while line.len() < bound_width {
                 line.push(String::new());
             }
         }
-        // Success
-        true
-    }
-    /// Generate a string from this sheet
-    pub fn generate_string(&mut self) -> String {
-        // Create res by iterating over the sheet
-        let mut res: String = String::new();
-        for row in &self.sheet {
-            let mut first_line = true;
-            for col in row {
-                if first_line {
-                    first_line = false;
-                } else {
-                    res.push(',');
-                    res.push(' ');
-                }
-                res.push_str(col);
-            }
-            res.push('\n');
-        }
-        res
+        self.recalculate();
+    }
+    /// Generate a delimited string (RFC 4180) from this sheet
+    pub fn generate_string(&mut self, delimiter: char) -> String {
+        csvformat::generate(&self.sheet, delimiter)
     }
     /// Load a vector literal
-    pub fn load_vector(&mut self, newsheet: &Vec<Vec<String>>) {
+    pub fn load_vector(&mut self, newsheet: &[Vec<String>]) {
         self.clear();
-        self.sheet = newsheet.clone();
+        self.sheet = newsheet.to_vec();
         self.selected = None;
+        self.recalculate();
     }
     /// Get the sheet bounds (y len, x len)
     /// If the sheet is not rectangular, bounds are based off first row
     pub fn bounds(&self) -> (usize, usize) {
-        if self.sheet.len() == 0 {
+        if self.sheet.is_empty() {
             return (0, 0);
         }
         (self.sheet.len(), self.sheet[0].len())
     }
+    /// Grow or shrink the sheet to a size (y len, x len), adding empty cells or removing cells at the bottom and right
+    fn resize(&mut self, size: (usize, usize)) -> Vec<Operation> {
+        let oldsize = self.bounds();
+        if size == oldsize {
+            return Vec::new();
+        }
+        self.sheet.truncate(size.0);
+        for line in &mut self.sheet {
+            line.resize(size.1, String::new());
+        }
+        while self.sheet.len() < size.0 {
+            self.sheet.push(vec![String::new(); size.1]);
+        }
+        self.hidden.truncate(size.0);
+        self.widths.truncate(size.1);
+        if let Some((row, col)) = self.selected {
+            self.selected = Some((cmp::min(row, size.0.saturating_sub(1)), cmp::min(col, size.1.saturating_sub(1))));
+        }
+        // References past the old or new bounds change (ranges are clamped to the bounds)
+        let (rows, cols) = (cmp::min(oldsize.0, size.0), cmp::min(oldsize.1, size.1));
+        self.move_formulas(
+            |coords| (coords.0 < size.0 && coords.1 < size.1).then_some(coords),
+            &[],
+            &[],
+            &[(rows..usize::MAX, 0..usize::MAX), (0..usize::MAX, cols..usize::MAX)]
+        );
+        vec![Operation::Resize(oldsize)]
+    }
     /// Get whether a point is in bounds (precisely, not rectangularly)
     pub fn in_bounds(&self, coords: (usize, usize)) -> bool {
         coords.0 < self.bounds().0 && coords.1 < self.sheet[coords.0].len()
@@ -97,6 +229,14 @@ impl Sheet {
         }
         Some(&self.sheet[coords.0][coords.1])
     }
+    /// Get the displayed value at a point in the sheet (the computed value for formula cells)
+    pub fn display_value(&self, coords: (usize, usize)) -> Option<String> {
+        let text = self.cell(coords)?;
+        if !formula::is_formula(text) {
+            return Some(text.to_string());
+        }
+        Some(self.values.get(&coords).map(|v| v.to_string()).unwrap_or_default())
+    }
     /// Move the coordinates of the selected cell
     pub fn move_selected_coords(&mut self, delta: (isize, isize)) {
         let Some(selected) = self.selected else {
@@ -105,7 +245,24 @@ impl Sheet {
             return;
         };
         // TODO: saturating subtract instead (bounds check in set_selected_coords means vim number commands do nothing if saturating)
-        let new0: usize = (selected.0 as isize + delta.0).try_into().unwrap_or(0);
+        let new0: usize = if delta.0 != 0 && self.has_hidden_rows() {
+            // Count only the rows that are shown
+            let mut row = selected.0;
+            for _ in 0..delta.0.unsigned_abs() {
+                let next = if delta.0 < 0 {
+                    (0..row).rev().find(|&r| !self.is_hidden(r))
+                } else {
+                    (row + 1..self.bounds().0).find(|&r| !self.is_hidden(r))
+                };
+                match next {
+                    Some(r) => row = r,
+                    None => return
+                }
+            }
+            row
+        } else {
+            (selected.0 as isize + delta.0).try_into().unwrap_or(0)
+        };
         let new1: usize = (selected.1 as isize + delta.1).try_into().unwrap_or(0);
         self.set_selected_coords((new0, new1));
     }
@@ -116,135 +273,396 @@ impl Sheet {
         }
         self.selected = Some(coords);
     }
+    /// Whether a row is hidden by filters
+    pub fn is_hidden(&self, row: usize) -> bool {
+        self.hidden.get(row).copied().unwrap_or(false)
+    }
+    /// Whether any rows are hidden by filters
+    pub fn has_hidden_rows(&self) -> bool {
+        self.hidden.contains(&true)
+    }
+    /// Set which rows are hidden by filters (empty to show every row)
+    pub fn set_hidden_rows(&mut self, hidden: Vec<bool>) {
+        self.hidden = hidden;
+    }
+    /// Get the display width of a column, if set
+    pub fn column_width(&self, col: usize) -> Option<usize> {
+        self.widths.get(col).copied().flatten()
+    }
+    /// Set the display width of a column (None for the default width)
+    pub fn set_column_width(&mut self, col: usize, width: Option<usize>) {
+        if col >= self.bounds().1 {
+            return;
+        }
+        if self.widths.len() <= col {
+            self.widths.resize(col + 1, None);
+        }
+        self.widths[col] = width;
+    }
+    /// Get the widest displayed value of a column
+    pub fn content_width(&self, col: usize) -> usize {
+        (0..self.bounds().0).map(|row| textwidth::display_width(&self.display_value((row, col)).unwrap_or_default())).max().unwrap_or(0)
+    }
+    /// Start selecting a range from the selected cell
+    pub fn start_range(&mut self, mode: SelectionMode) {
+        self.anchor = self.selected;
+        self.selection_mode = mode;
+    }
+    /// Stop selecting a range (only the selected cell stays selected)
+    pub fn clear_range(&mut self) {
+        self.anchor = None;
+    }
+    /// Get the selected range as (top left, bottom right), inclusive and within the sheet bounds
+    /// (just the selected cell if no range is being selected)
+    pub fn selection_bounds(&self) -> Option<((usize, usize), (usize, usize))> {
+        let selected = self.selected?;
+        let anchor = self.anchor.unwrap_or(selected);
+        let (rowmax, colmax) = (self.bounds().0.checked_sub(1)?, self.bounds().1.checked_sub(1)?);
+        let top = cmp::min(cmp::min(selected.0, anchor.0), rowmax);
+        let bottom = cmp::min(cmp::max(selected.0, anchor.0), rowmax);
+        let (left, right) = match (self.anchor, self.selection_mode) {
+            (Some(_), SelectionMode::Rows) => (0, colmax),
+            _ => (cmp::min(cmp::min(selected.1, anchor.1), colmax), cmp::min(cmp::max(selected.1, anchor.1), colmax))
+        };
+        Some(((top, left), (bottom, right)))
+    }
+    /// Whether a cell is in the selected range
+    pub fn in_selection(&self, coords: (usize, usize)) -> bool {
+        match self.selection_bounds() {
+            Some((topleft, bottomright)) => {
+                (topleft.0..=bottomright.0).contains(&coords.0) && (topleft.1..=bottomright.1).contains(&coords.1)
+            }
+            None => false
+        }
+    }
     /// Get the value of the selected cell
     pub fn selected_cell_value(&self) -> Option<&str> {
-        if self.selected.is_none() {
-            return None;
-        }
-        self.cell(self.selected.unwrap())
+        self.cell(self.selected?)
     }
-    /// Set the value of a cell
-    pub fn set_cell_value(&mut self, coords: (usize, usize), newval: String) {
-        if !self.in_bounds(coords) {
-            return;
+    /// Make a change to the sheet, returning the operations that undo it (in order; none if nothing changed)
+    pub fn apply(&mut self, op: Operation) -> Vec<Operation> {
+        match op {
+            Operation::SetCells(cells) => self.set_cells(cells),
+            Operation::InsertRow(rowcoord, cells) => self.insert_row(rowcoord, cells),
+            Operation::DeleteRow(rowcoord) => self.delete_row(rowcoord),
+            Operation::InsertColumn(colcoord, cells, width) => self.insert_column(colcoord, cells, width),
+            Operation::DeleteColumn(colcoord) => self.delete_column(colcoord),
+            Operation::PermuteRows(rowstart, order) => self.permute_rows(rowstart, &order),
+            Operation::PermuteColumns(colstart, order) => self.permute_columns(colstart, &order),
+            Operation::Resize(size) => self.resize(size)
         }
-        self.sheet[coords.0][coords.1] = newval;
     }
-    /// Set the value of the selected cell
-    pub fn set_selected_cell_value(&mut self, newval: String) {
-        if self.selected.is_none() {
-            return;
+    /// Set the values of cells (cells out of bounds are skipped)
+    fn set_cells(&mut self, cells: Vec<((usize, usize), String)>) -> Vec<Operation> {
+        let mut old: Vec<((usize, usize), String)> = Vec::new();
+        for (coords, newval) in cells {
+            if !self.in_bounds(coords) || self.sheet[coords.0][coords.1] == newval {
+                continue;
+            }
+            old.push((coords, mem::replace(&mut self.sheet[coords.0][coords.1], newval)));
+            self.update_formula(coords);
         }
-        self.set_cell_value(self.selected.unwrap(), newval);
+        let changed: Vec<(usize, usize)> = old.iter().map(|&(coords, _)| coords).collect();
+        self.recalculate_cells(&changed);
+        // Set back in reverse, in case a cell was set more than once
+        old.reverse();
+        undo_texts(old)
     }
     /// Delete a row at a coordinate
-    pub fn delete_row(&mut self, rowcoord: usize) -> bool {
+    fn delete_row(&mut self, rowcoord: usize) -> Vec<Operation> {
         if rowcoord >= self.bounds().0 || self.bounds().0 <= 1 {
-            return false;
+            return Vec::new();
         }
+        let cells = self.sheet[rowcoord].clone();
+        let shifted = self.shift_formulas(Shift::DeleteRow(rowcoord));
         self.sheet.remove(rowcoord);
+        if rowcoord < self.hidden.len() {
+            self.hidden.remove(rowcoord);
+        }
         if let Some((row, col)) = self.selected {
             if row >= self.bounds().0 {
                 self.selected = Some((row - 1, col));
             }
         }
-        true
+        self.move_formulas(
+            |(row, col)| match row.cmp(&rowcoord) {
+                Ordering::Less => Some((row, col)),
+                Ordering::Equal => None,
+                Ordering::Greater => Some((row - 1, col))
+            },
+            &shifted,
+            &[],
+            &[(rowcoord..usize::MAX, 0..usize::MAX)]
+        );
+        let mut res = vec![Operation::InsertRow(rowcoord, cells)];
+        res.extend(undo_texts(shifted));
+        res
     }
     /// Delete a column at a coordinate
-    pub fn delete_column(&mut self, colcoord: usize) -> bool {
+    fn delete_column(&mut self, colcoord: usize) -> Vec<Operation> {
         if colcoord >= self.bounds().1 || self.bounds().1 <= 1 {
-            return false;
+            return Vec::new();
         }
+        let cells: Vec<String> = self.sheet.iter().map(|row| row.get(colcoord).cloned().unwrap_or_default()).collect();
+        let width = self.column_width(colcoord);
+        let shifted = self.shift_formulas(Shift::DeleteColumn(colcoord));
         for row in &mut self.sheet {
             if colcoord >= row.len() {
                 continue;
             }
             row.remove(colcoord);
         }
+        if colcoord < self.widths.len() {
+            self.widths.remove(colcoord);
+        }
         if let Some((row, col)) = self.selected {
             if col >= self.bounds().1 {
                 self.selected = Some((row, col - 1));
             }
         }
-        true
+        self.move_formulas(
+            |(row, col)| match col.cmp(&colcoord) {
+                Ordering::Less => Some((row, col)),
+                Ordering::Equal => None,
+                Ordering::Greater => Some((row, col - 1))
+            },
+            &shifted,
+            &[],
+            &[(0..usize::MAX, colcoord..usize::MAX)]
+        );
+        let mut res = vec![Operation::InsertColumn(colcoord, cells, width)];
+        res.extend(undo_texts(shifted));
+        res
     }
-    /// Insert a row at a coordinate
-    pub fn insert_row(&mut self, rowcoord: usize) -> bool {
+    /// Insert a row with its cells (empty for a new row) at a coordinate
+    fn insert_row(&mut self, rowcoord: usize, mut cells: Vec<String>) -> Vec<Operation> {
         if rowcoord > self.bounds().0 {
-            return false;
+            return Vec::new();
+        }
+        let shifted = self.shift_formulas(Shift::InsertRow(rowcoord));
+        cells.resize(self.bounds().1, String::new());
+        self.sheet.insert(rowcoord, cells);
+        if rowcoord < self.hidden.len() {
+            self.hidden.insert(rowcoord, false); // New rows are shown
         }
-        self.sheet.insert(rowcoord, vec![String::new(); self.bounds().1]);
-        true
+        self.move_formulas(
+            |(row, col)| Some((if row >= rowcoord { row + 1 } else { row }, col)),
+            &shifted,
+            &(0..self.bounds().1).map(|col| (rowcoord, col)).collect::<Vec<_>>(),
+            &[(rowcoord..usize::MAX, 0..usize::MAX)]
+        );
+        let mut res = vec![Operation::DeleteRow(rowcoord)];
+        res.extend(undo_texts(shifted));
+        res
     }
-    /// Insert a column at a coordinate
-    pub fn insert_column(&mut self, colcoord: usize) -> bool {
+    /// Insert a column with its cells from the top (empty for a new column) and display width at a coordinate
+    fn insert_column(&mut self, colcoord: usize, cells: Vec<String>, width: Option<usize>) -> Vec<Operation> {
         if colcoord > self.bounds().1 {
-            return false;
+            return Vec::new();
         }
+        let shifted = self.shift_formulas(Shift::InsertColumn(colcoord));
+        let mut cells = cells.into_iter();
         for row in &mut self.sheet {
+            let text = cells.next().unwrap_or_default();
             if colcoord > row.len() {
                 continue;
             }
-            row.insert(colcoord, String::new());
+            row.insert(colcoord, text);
+        }
+        if colcoord < self.widths.len() || width.is_some() {
+            self.widths.resize(cmp::max(self.widths.len(), colcoord), None);
+            self.widths.insert(colcoord, width);
+        }
+        self.move_formulas(
+            |(row, col)| Some((row, if col >= colcoord { col + 1 } else { col })),
+            &shifted,
+            &(0..self.bounds().0).map(|row| (row, colcoord)).collect::<Vec<_>>(),
+            &[(0..usize::MAX, colcoord..usize::MAX)]
+        );
+        let mut res = vec![Operation::DeleteColumn(colcoord)];
+        res.extend(undo_texts(shifted));
+        res
+    }
+    /// Get the order of the rows from rowstart to rowend, inclusive, sorted by the values of the key columns (stable)
+    /// (see Operation::PermuteRows), or None if the rows or keys are out of bounds
+    pub fn sorted_row_order(&self, rowstart: usize, rowend: usize, keys: &[SortKey]) -> Option<Vec<usize>> {
+        if rowstart > rowend || rowend >= self.bounds().0 || keys.is_empty() || keys.iter().any(|k| k.index >= self.bounds().1) {
+            return None;
         }
-        true
+        // Compare by the displayed values, so formulas sort by their results
+        let mut order: Vec<(usize, Vec<String>)> = (rowstart..=rowend).map(|row| {
+            (row, keys.iter().map(|k| self.display_value((row, k.index)).unwrap_or_default()).collect())
+        }).collect();
+        order.sort_by(|a, b| compare_by_keys(keys, &a.1, &b.1));
+        Some(order.into_iter().map(|(row, _)| row).collect())
     }
-    /// Sort a column at a coordinate
-    pub fn sort_column(&mut self, colcoord: usize) -> bool {
-        self.sort_column_bounded(colcoord, 0, self.bounds().0 - 1)
+    /// Get the order of the columns from colstart to colend, inclusive, sorted by the values of the key rows (stable)
+    /// (see Operation::PermuteColumns), or None if the columns or keys are out of bounds
+    pub fn sorted_column_order(&self, colstart: usize, colend: usize, keys: &[SortKey]) -> Option<Vec<usize>> {
+        if colstart > colend || colend >= self.bounds().1 || keys.is_empty() || keys.iter().any(|k| k.index >= self.bounds().0) {
+            return None;
+        }
+        let mut order: Vec<(usize, Vec<String>)> = (colstart..=colend).map(|col| {
+            (col, keys.iter().map(|k| self.display_value((k.index, col)).unwrap_or_default()).collect())
+        }).collect();
+        order.sort_by(|a, b| compare_by_keys(keys, &a.1, &b.1));
+        Some(order.into_iter().map(|(col, _)| col).collect())
     }
-    /// Sort the region of a column from rowstart to rowend, inclusive, by string
-    pub fn sort_column_bounded(&mut self, colcoord: usize, rowstart: usize, rowend: usize) -> bool {
-        // TODO: impl sort range and number-based sort
-        if colcoord >= self.bounds().1 || rowstart > rowend || rowend >= self.bounds().0 {
-            return false;
+    /// Reorder the rows starting at rowstart, so the i-th of them becomes the row that was at order[i]
+    fn permute_rows(&mut self, rowstart: usize, order: &[usize]) -> Vec<Operation> {
+        let mut taken: Vec<Vec<String>> = order.iter().map(|&row| std::mem::take(&mut self.sheet[row])).collect();
+        for (i, line) in taken.drain(..).enumerate() {
+            self.sheet[rowstart + i] = line;
         }
-        let mut thisregion: Vec<String> = Vec::new();
-        for row in &mut self.sheet[rowstart..=rowend] {
-            if colcoord >= row.len() {
-                // TODO: err
-                return false; // Cannot sort when not rectangular
+        if !self.hidden.is_empty() {
+            self.hidden.resize(self.bounds().0, false);
+            let taken: Vec<bool> = order.iter().map(|&row| self.hidden[row]).collect();
+            self.hidden.splice(rowstart..rowstart + order.len(), taken);
+        }
+        // Formulas move as is, so only those reading the reordered rows change
+        let inverse = inverse_order(rowstart, order);
+        let rows = rowstart..rowstart + order.len();
+        self.move_formulas(
+            |(row, col)| Some((if rows.contains(&row) { inverse[row - rowstart] } else { row }, col)),
+            &[],
+            &[],
+            &[(rows.clone(), 0..usize::MAX)]
+        );
+        vec![Operation::PermuteRows(rowstart, inverse)]
+    }
+    /// Reorder the columns starting at colstart, so the i-th of them becomes the column that was at order[i]
+    fn permute_columns(&mut self, colstart: usize, order: &[usize]) -> Vec<Operation> {
+        for line in &mut self.sheet {
+            if colstart + order.len() > line.len() {
+                continue; // Cannot reorder when not rectangular
+            }
+            let taken: Vec<String> = order.iter().map(|&col| std::mem::take(&mut line[col])).collect();
+            for (i, text) in taken.into_iter().enumerate() {
+                line[colstart + i] = text;
             }
-            thisregion.push(row[colcoord].clone());
         }
-        thisregion.sort();
-        for (i, row) in &mut self.sheet[rowstart..=rowend].iter_mut().enumerate() {
-            row[colcoord] = thisregion[i].clone();
+        if !self.widths.is_empty() {
+            self.widths.resize(self.bounds().1, None);
+            let taken: Vec<Option<usize>> = order.iter().map(|&col| self.widths[col]).collect();
+            self.widths.splice(colstart..colstart + order.len(), taken);
         }
-        true
+        let inverse = inverse_order(colstart, order);
+        let cols = colstart..colstart + order.len();
+        self.move_formulas(
+            |(row, col)| Some((row, if cols.contains(&col) { inverse[col - colstart] } else { col })),
+            &[],
+            &[],
+            &[(0..usize::MAX, cols.clone())]
+        );
+        vec![Operation::PermuteColumns(colstart, inverse)]
+    }
+}
+
+/// Get the operation setting cells back to their texts (none if there are no cells)
+fn undo_texts(cells: Vec<((usize, usize), String)>) -> Vec<Operation> {
+    if cells.is_empty() {
+        Vec::new()
+    } else {
+        vec![Operation::SetCells(cells)]
+    }
+}
+
+/// Get the order undoing a reordering from start (see Operation::PermuteRows)
+fn inverse_order(start: usize, order: &[usize]) -> Vec<usize> {
+    let mut res = vec![0; order.len()];
+    for (i, &from) in order.iter().enumerate() {
+        res[from - start] = start + i;
     }
-    /// Sort the region of a column from rowstart to rowend, inclusive, by number
-    pub fn sort_column_bounded_num(&mut self, colcoord: usize, rowstart: usize, rowend: usize) -> bool {
-        // TODO: impl sort range
-        if colcoord >= self.bounds().1 || rowstart > rowend || rowend >= self.bounds().0 {
-            return false;
+    res
+}
+
+/// Compare two lists of key values, by the first key that differs
+fn compare_by_keys(keys: &[SortKey], a: &[String], b: &[String]) -> Ordering {
+    for (i, key) in keys.iter().enumerate() {
+        let ord = key.compare(&a[i], &b[i]);
+        if ord != Ordering::Equal {
+            return ord;
         }
-        let mut thisregion: Vec<(String, f32)> = Vec::new();
-        for row in &mut self.sheet[rowstart..=rowend] {
-            if colcoord >= row.len() {
-                // TODO: err
-                return false; // Cannot sort when not rectangular
+    }
+    Ordering::Equal
+}
+
+impl Default for Sheet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(rows: &[&[&str]]) -> Vec<Vec<String>> {
+        rows.iter().map(|row| row.iter().map(|text| text.to_string()).collect()).collect()
+    }
+
+    /// Check the values kept up to date by a change are those of recomputing the whole sheet
+    fn assert_recalculated(sheet: &Sheet) {
+        let mut fresh = Sheet::new();
+        fresh.load_vector(&sheet.sheet);
+        for row in 0..sheet.bounds().0 {
+            for col in 0..sheet.bounds().1 {
+                assert_eq!(sheet.display_value((row, col)), fresh.display_value((row, col)), "cell ({}, {})", row, col);
             }
-            thisregion.push((row[colcoord].clone(), row[colcoord].clone().parse::<f32>().unwrap_or(0.0)));
         }
-        // TODO: fix with sort_by: v.sort_by(|a, b| a.partial_cmp(b).unwrap());
-        thisregion.sort_by(|k, j| k.1.partial_cmp(&j.1).unwrap()); // Sort by the f32 (number) component
-        for (i, row) in &mut self.sheet[rowstart..=rowend].iter_mut().enumerate() {
-            row[colcoord] = thisregion[i].0.clone(); // Put the string component
+    }
+
+    fn sample() -> Sheet {
+        let mut sheet = Sheet::new();
+        sheet.load_vector(&texts(&[
+            &["1", "=A1*2", "=SUM(A1:A3)", "=$A$2"],
+            &["2", "=A2*2", "=B1+B2", "=C1&\"!\""],
+            &["3", "=A3*2", "=SUM($A$1:A3)", "=C3/A1"],
+            &["=A1+A2", "=D2", "=D3", "=A4"]
+        ]));
+        sheet
+    }
+
+    #[test]
+    fn keeps_values_after_structural_changes() {
+        let ops = [
+            Operation::InsertRow(1, Vec::new()),
+            Operation::InsertRow(4, texts(&[&["5", "=A1", "", ""]]).remove(0)),
+            Operation::DeleteRow(0),
+            Operation::DeleteRow(2),
+            Operation::InsertColumn(1, Vec::new(), None),
+            Operation::InsertColumn(0, vec!["=B1".to_string(), "9".to_string()], None),
+            Operation::DeleteColumn(0),
+            Operation::DeleteColumn(2),
+            Operation::PermuteRows(0, vec![2, 0, 3, 1]),
+            Operation::PermuteRows(1, vec![2, 1]),
+            Operation::PermuteColumns(1, vec![3, 1, 2]),
+            Operation::Resize((2, 2)),
+            Operation::Resize((6, 5))
+        ];
+        for op in ops {
+            let mut sheet = sample();
+            let undo = sheet.apply(op.clone());
+            assert_recalculated(&sheet);
+            for op in undo {
+                sheet.apply(op);
+            }
+            assert_recalculated(&sheet);
+            // (a sheet only shrinks to undo growing it, once the new cells were set back to empty)
+            if !matches!(op, Operation::Resize(_)) {
+                assert_eq!(sheet.sheet, sample().sheet, "undoing {:?}", op);
+            }
         }
-        true
     }
-    /// Make this sheet match another sheet
-    pub fn set_equal(&mut self, other: &Sheet) {
-        self.clear();
-        self.selected = other.selected;
-        self.sheet = other.sheet.clone();
-    }
-    /// Copy this sheet
-    pub fn clone(&self) -> Sheet {
-        // TODO: impl clone and match
-        let mut res: Sheet = Sheet::new();
-        res.set_equal(&self);
-        res
+
+    #[test]
+    fn breaks_circular_references_after_structural_changes() {
+        let mut sheet = Sheet::new();
+        sheet.load_vector(&texts(&[&["=$B$1", "=A1"], &["1", "2"]]));
+        assert_eq!(sheet.display_value((0, 0)).unwrap(), "#CIRC!");
+        sheet.apply(Operation::InsertColumn(1, Vec::new(), None));
+        assert_recalculated(&sheet);
+        assert_eq!(sheet.display_value((0, 0)).unwrap(), "");
+        assert_eq!(sheet.display_value((0, 2)).unwrap(), "");
     }
 }
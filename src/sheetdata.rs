@@ -1,136 +1,432 @@
-use std::{ fs, collections::VecDeque };
-use crate::configdata::ConfigData;
-use crate::sheet::Sheet;
+use std::{ cmp, fs, path::{ Path, PathBuf } };
+use crate::configdata::{ self, ConfigData };
+use crate::sheet::{ SelectionMode, Sheet };
+use crate::csvformat;
+use crate::formula;
+use crate::sortkey::SortKey;
+use crate::search::{ Matcher, ReplaceScope, Search, Substitution };
+use crate::register::{ Register, RegisterKind, Registers };
+use crate::filter::Filter;
+use crate::history::{ self, History, Operation, StateInfo, Step, Travel };
+
+/// How to fill the selected range
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fill {
+    Down, // Copy the top row down (moving relative references in formulas)
+    Right, // Copy the left column right (moving relative references in formulas)
+    Value(String) // Set every cell to a value
+}
 
 /// Stores the data for managing the sheet's history/file status, and the sheet itself
 pub struct SheetData {
     pub file_path: String,
+    pub delimiter: char, // The delimiter the file was read with, and will be written with
+    saved_delimiter: char, // The delimiter of the file as last loaded or saved (changing the delimiter is unsaved until saved)
+    widths_changed: bool, // Whether column widths were set since the file was last loaded or saved (they are saved with it)
     sheet: Sheet,
-    history: VecDeque<Sheet>, // Stack of prior sheet states
-    historyframe: i32, // The current index of history (if equals history length, then at new frame)
-    pub unsaved: bool
+    history: History, // Steps undoing and redoing changes to the sheet
+    pub unsaved: bool,
+    search: Option<Search>, // The last search, for moving between matches
+    registers: Registers, // Yanked and cut cells (kept when opening another file)
+    clipboard_text: Option<String>, // Tab-separated values of the last yank, until copied to the system clipboard
+    filters: Vec<Filter>, // Filters hiding rows (a row is shown if it passes all of them)
+    frozen: (usize, usize), // Rows and columns always shown at the top and left (and never hidden by filters)
+    pub status_message: String // Message shown below the sheet on the next render
 }
 
 impl SheetData {
     pub fn new() -> SheetData {
         SheetData {
             file_path: "new_file".to_string(),
+            delimiter: ',',
+            saved_delimiter: ',',
+            widths_changed: false,
             sheet: Sheet::new(),
-            history: VecDeque::new(),
-            historyframe: -1,
-            unsaved: false
+            history: History::default(),
+            unsaved: false,
+            search: None,
+            registers: Registers::default(),
+            clipboard_text: None,
+            filters: Vec::new(),
+            frozen: (0, 0),
+            status_message: String::new()
         }
     }
-    /// DBG: Get the history info (length and frame)
-    pub fn dbg_get_history_info(&self) -> (usize, i32) {
-        (self.history.len(), self.historyframe)
-    }
     /// Clear the sheet state
     /// (reset all history; call this everywhere where the sheet is reset BEFORE resetting it)
     fn clear_sheet_state(&mut self) {
         self.sheet.clear();
-        self.history.clear();
+        self.filters.clear();
+        self.history.clear(true);
+        self.widths_changed = false;
         self.unsaved = false;
-        self.historyframe = -1;
+    }
+    /// Make a change to the sheet, recording how to undo it as part of the current undo step
+    fn edit(&mut self, op: Operation) {
+        let selected = self.sheet.selected;
+        let inverse = self.sheet.apply(op);
+        self.history.record(inverse, selected);
     }
     /// Update a sheet state
-    /// (set to unsaved and add in the history; call this everywhere the sheet is changed BEFORE making the change)
+    /// (end the current undo step unless in a transaction; call this everywhere the sheet is changed AFTER making the change)
     fn update_sheet_state(&mut self, config: &ConfigData) {
-        self.unsaved = true;
-        // Erase history after the current frame
-        if self.historyframe > -1 {
-            self.history.truncate(self.historyframe as usize + 1);
-        } else {
-            self.history.clear();
+        self.history.end_step(history_limit(config));
+        self.refresh_unsaved();
+    }
+    /// Update whether there are unsaved changes (to the sheet since its saved state in the history, to the delimiter, or to column widths)
+    fn refresh_unsaved(&mut self) {
+        self.unsaved = self.history.is_unsaved() || self.delimiter != self.saved_delimiter || self.widths_changed;
+    }
+    /// Start a batch of changes that is undone as a single step
+    /// (transactions can nest; the step ends when the outermost one is committed)
+    pub fn begin_transaction(&mut self) {
+        self.history.begin();
+    }
+    /// End a batch of changes started with begin_transaction
+    pub fn commit_transaction(&mut self, config: &ConfigData) {
+        self.history.commit(history_limit(config));
+        self.refresh_unsaved();
+    }
+    /// Undo or redo the change made by a state of the history
+    fn apply_step(&mut self, id: u64, undo: bool) {
+        let mut step = self.history.take_step(id);
+        let mut reverse = Vec::new();
+        while let Some(op) = step.ops.pop() {
+            reverse.extend(self.sheet.apply(op).into_iter().rev());
         }
-        // Add this after
-        self.history.push_back(self.sheet.clone());
-        self.historyframe += 1;
-        if self.history.len() > config.get_value("historysize").unwrap_or(10).try_into().unwrap_or(0) {
-            // Delete from the front
-            self.history.pop_front();
-            self.historyframe -= 1;
+        let selected = self.sheet.selected;
+        if let Some(coords) = step.selected {
+            self.sheet.set_selected_coords(coords);
         }
+        self.history.put_step(id, Step { ops: reverse, selected }, undo);
     }
-    /// Undo (move back in history) and return whether successful
-    pub fn undo(&mut self) -> bool {
-        self.unsaved = true;
-        // Save the current state if needed
-        if self.historyframe <= 0 {
+    /// Go to a state of the history (undoing and redoing changes along the way), and return whether successful
+    fn go_to_state(&mut self, target: u64) -> bool {
+        let Some((undo, redo)) = self.history.path_to(target) else {
+            return false;
+        };
+        if undo.is_empty() && redo.is_empty() {
             return false;
         }
-        self.historyframe -= 1;
-        match self.history.get(self.historyframe as usize) {
-            None => {
-                false
-            },
-            Some(thissheet) => {
-                self.sheet.set_equal(&thissheet);
-                true
-            }
+        for id in undo {
+            self.apply_step(id, true);
+        }
+        for id in redo {
+            self.apply_step(id, false);
         }
+        self.sheet.clear_range();
+        self.apply_filters();
+        self.refresh_unsaved();
+        true
     }
-    /// Redo (move forward in history) and return whether successful
+    /// Undo (move back in history) and return whether successful
+    pub fn undo(&mut self) -> bool {
+        self.history.undo_target().is_some_and(|target| self.go_to_state(target))
+    }
+    /// Redo (move forward into the branch last made or undone) and return whether successful
     pub fn redo(&mut self) -> bool {
-        self.unsaved = true;
-        if self.historyframe >= 0 && self.historyframe as usize >= self.history.len() - 1 {
-            return false;
-        }
-        self.historyframe += 1;
-        match self.history.get(self.historyframe as usize) {
-            None => {
-                self.historyframe -= 1; // Go back
-                false
-            },
-            Some(thissheet) => {
-                self.sheet.set_equal(&thissheet);
-                true
+        self.history.redo_target().is_some_and(|target| self.go_to_state(target))
+    }
+    /// Go back (or forward, if later) through the states in the order they were made, across branches
+    /// (like vim's `g-`/`g+` and `:earlier`/`:later`), and return whether successful
+    pub fn travel_history(&mut self, travel: Travel, later: bool) -> bool {
+        let target = match travel {
+            Travel::States(count) => {
+                let count = count as isize;
+                self.history.chronological_target(if later { count } else { -count })
             }
-        }
+            Travel::Seconds(seconds) => {
+                let seconds = seconds as i64;
+                self.history.time_target(if later { seconds } else { -seconds })
+            }
+        };
+        self.go_to_state(target)
     }
-    /// Load a file, return whether successful
-    pub fn load_file(&mut self, path: &str) -> bool {
-        self.clear_sheet_state();
-        self.file_path = path.to_string();
+    /// Get the states of the history, oldest first
+    pub fn history_states(&self) -> Vec<StateInfo> {
+        self.history.states()
+    }
+    /// Load a file, or return a description of the error
+    /// The delimiter is taken from the file extension if possible, and sniffed from the contents otherwise
+    pub fn load_file(&mut self, path: &str, config: &ConfigData) -> Result<(), String> {
+        self.load_file_with_delimiter(path, None, config)
+    }
+    /// Load a file with a given delimiter (or the detected one if None), or return a description of the error
+    /// Its undo history is loaded too if the `undofile` config value is set and the history was saved with this version of the file
+    /// (the open sheet is only replaced once the file has been read and parsed)
+    pub fn load_file_with_delimiter(&mut self, path: &str, delimiter: Option<char>, config: &ConfigData) -> Result<(), String> {
         // Get the file
-        let read_res = fs::read_to_string(path);
-        if read_res.is_err() {
-            return false;
-        }
-        let res = read_res.unwrap().replace("\r\n", "\n").replace("\r", "\n");
+        let res = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        // Determine the delimiter
+        let delimiter = delimiter
+            .or_else(|| csvformat::delimiter_from_extension(path))
+            .unwrap_or_else(|| csvformat::sniff_delimiter(&res));
+        let rows = csvformat::parse(&res, delimiter).map_err(|e| e.to_string())?;
         // Update the sheet
-        if self.sheet.load_string(res) {
-            self.unsaved = false;
-            true
-        } else {
-            false
+        self.clear_sheet_state();
+        self.file_path = path.to_string();
+        self.delimiter = delimiter;
+        self.saved_delimiter = delimiter;
+        self.sheet.load_rows(rows);
+        self.load_column_widths();
+        if undo_file_enabled(config) {
+            let hash = history::content_hash(&res);
+            if let Some(loaded) = fs::read_to_string(self.undo_path()).ok().and_then(|text| History::from_text(&text, hash)) {
+                self.history = loaded;
+            }
         }
+        self.unsaved = false;
+        Ok(())
     }
     /// Load a vector literal
-    pub fn load_vector(&mut self, newsheet: &Vec<Vec<String>>) {
+    pub fn load_vector(&mut self, newsheet: &[Vec<String>]) {
         self.clear_sheet_state();
         self.file_path = "generated_file".to_string();
+        self.delimiter = ',';
+        self.saved_delimiter = ',';
         self.sheet.load_vector(newsheet);
+        self.history.clear(false);
         self.unsaved = true;
     }
     /// Save to a file, return whether successful
-    pub fn save_file(&mut self, path: &str) -> bool {
+    /// The column widths are saved next to it, and the undo history too if the `undofile` config value is set
+    pub fn save_file(&mut self, path: &str, config: &ConfigData) -> bool {
         if path == self.file_path && !self.unsaved {
             // Same file, so do not save
             return false; // todo: better error message ("already saved")
         }
-        self.file_path = path.to_string();
+        // Saving to a new file: honor its extension if it implies a delimiter
+        let delimiter = match csvformat::delimiter_from_extension(path) {
+            Some(delimiter) if path != self.file_path => delimiter,
+            _ => self.delimiter
+        };
         // Generate the string
-        let res: String = self.sheet.generate_string();
+        let res: String = self.sheet.generate_string(delimiter);
         // Open the file
-        let write_res = fs::write(path, res);
+        let write_res = fs::write(path, &res);
         if write_res.is_err() {
             return false;
         }
         // Now the file has been saved
+        self.file_path = path.to_string();
+        self.delimiter = delimiter;
+        self.saved_delimiter = delimiter;
+        self.save_column_widths();
+        self.widths_changed = false;
+        self.history.mark_saved();
+        if undo_file_enabled(config) {
+            let _ = fs::write(self.undo_path(), self.history.to_text(history::content_hash(&res)));
+        }
         self.unsaved = false;
         true
     }
+    /// Get the path of the file storing the undo history of the current file (ex. `.data.csv.sheatfish_undo.csv`)
+    fn undo_path(&self) -> PathBuf {
+        sidecar_path(&self.file_path, "sheatfish_undo.csv")
+    }
+    /// Get the path of the file storing the column widths of the current file (ex. `.data.csv.sheatfish_widths.csv`)
+    fn column_widths_path(&self) -> PathBuf {
+        sidecar_path(&self.file_path, "sheatfish_widths.csv")
+    }
+    /// Load the column widths of the current file, if it has any
+    fn load_column_widths(&mut self) {
+        let contents = fs::read_to_string(self.column_widths_path()).unwrap_or_default();
+        for line in contents.lines() {
+            // Parse: "column, width"
+            if let Some((col, width)) = line.split_once(',') {
+                if let (Ok(col), Ok(width)) = (col.trim().parse::<usize>(), width.trim().parse::<usize>()) {
+                    self.sheet.set_column_width(col, Some(width));
+                }
+            }
+        }
+    }
+    /// Save the column widths of the current file (removing the widths file if none are set)
+    fn save_column_widths(&self) {
+        let mut res = String::new();
+        for col in 0..self.bounds().1 {
+            if let Some(width) = self.sheet.column_width(col) {
+                res.push_str(&format!("{}, {}\n", col, width));
+            }
+        }
+        let path = self.column_widths_path();
+        if !res.is_empty() {
+            let _ = fs::write(path, res);
+        } else if path.is_file() {
+            let _ = fs::remove_file(path);
+        }
+    }
+    /// Get the display width of a column (its own width, or else the `maxcellwidth` config value)
+    pub fn column_width(&self, col: usize, config: &ConfigData) -> usize {
+        self.sheet.column_width(col).unwrap_or(config.get_value("maxcellwidth").unwrap_or(5).try_into().unwrap_or(5))
+    }
+    /// Set the display width of a column (None for the default width), to be saved with the file
+    pub fn set_column_width(&mut self, col: usize, width: Option<usize>) {
+        self.sheet.set_column_width(col, width.map(|w| cmp::max(w, 1)));
+        self.widths_changed = true;
+        self.refresh_unsaved();
+    }
+    /// Widen (or narrow, if negative) a column
+    pub fn resize_column(&mut self, col: usize, delta: isize, config: &ConfigData) {
+        let width = self.column_width(col, config).saturating_add_signed(delta);
+        self.set_column_width(col, Some(width));
+    }
+    /// Size columns to fit their displayed values, up to a maximum width
+    pub fn autofit_columns(&mut self, cols: &[usize], maxwidth: usize) {
+        for &col in cols {
+            self.sheet.set_column_width(col, Some(self.sheet.content_width(col).clamp(1, cmp::max(maxwidth, 1))));
+        }
+        self.widths_changed = true;
+        self.refresh_unsaved();
+    }
+    /// Set the delimiter used when saving (the file is unsaved while it differs from the delimiter of the saved file)
+    pub fn set_delimiter(&mut self, delimiter: char) {
+        self.delimiter = delimiter;
+        self.refresh_unsaved();
+    }
+    /// Start a search (backward if started with '?') and move to the first match
+    pub fn start_search(&mut self, pattern: &str, backward: bool) -> Result<(), String> {
+        let matcher = Matcher::parse(pattern)?;
+        self.search = Some(Search { pattern: pattern.to_string(), matcher, backward });
+        self.search_next(false);
+        Ok(())
+    }
+    /// Stop highlighting the matches of the last search
+    pub fn clear_search(&mut self) {
+        self.search = None;
+    }
+    /// Whether a cell matches the last search (by its text, the same one replace changes, so formulas match their formula)
+    pub fn is_search_match(&self, coords: (usize, usize)) -> bool {
+        match &self.search {
+            Some(search) => search.matcher.is_match(self.cell(coords).unwrap_or_default()),
+            None => false
+        }
+    }
+    /// Move to the next match of the last search (or the previous one if reverse), wrapping around,
+    /// and set the status message (ex. "match 3 of 17")
+    pub fn search_next(&mut self, reverse: bool) {
+        let Some(search) = &self.search else {
+            self.status_message = "No previous search.".to_string();
+            return;
+        };
+        // Find all matches, in row-major order
+        let mut matches: Vec<(usize, usize)> = Vec::new();
+        for row in (0..self.bounds().0).filter(|&r| !self.sheet.is_hidden(r)) {
+            for col in 0..self.bounds().1 {
+                if search.matcher.is_match(self.cell((row, col)).unwrap_or_default()) {
+                    matches.push((row, col));
+                }
+            }
+        }
+        if matches.is_empty() {
+            self.status_message = format!("Pattern not found: {}", search.pattern);
+            return;
+        }
+        // Move forward or backward from the selected cell
+        let backward = search.backward != reverse;
+        let selected = self.selected().unwrap_or((0, 0));
+        let (index, wrapped) = if backward {
+            match matches.iter().rposition(|m| *m < selected) {
+                Some(i) => (i, false),
+                None => (matches.len() - 1, true)
+            }
+        } else {
+            match matches.iter().position(|m| *m > selected) {
+                Some(i) => (i, false),
+                None => (0, true)
+            }
+        };
+        self.set_selected_coords(matches[index]);
+        self.status_message = format!(
+            "match {} of {}{}",
+            index + 1,
+            matches.len(),
+            if !wrapped { "" } else if backward { " (search hit TOP, continuing at BOTTOM)" } else { " (search hit BOTTOM, continuing at TOP)" }
+        );
+    }
+    /// Add a filter (on top of the current ones), returning a description of the error if it cannot apply
+    pub fn add_filter(&mut self, filter: Filter) -> Result<(), String> {
+        if filter.column >= self.bounds().1 {
+            return Err(format!("Column {} is out of bounds.", filter.column));
+        }
+        self.filters.push(filter);
+        self.apply_filters();
+        Ok(())
+    }
+    /// Remove all filters, showing every row
+    pub fn clear_filters(&mut self) {
+        self.filters.clear();
+        self.apply_filters();
+    }
+    /// Get the active filters
+    pub fn filters(&self) -> &[Filter] {
+        &self.filters
+    }
+    /// Hide the rows that do not pass the filters (only run when the filters or frozen rows change, so edited rows stay shown)
+    fn apply_filters(&mut self) {
+        let hidden: Vec<bool> = if self.filters.is_empty() {
+            Vec::new()
+        } else {
+            (0..self.bounds().0).map(|row| {
+                row >= self.frozen.0 && !self.filters.iter().all(|f| f.predicate.matches(&self.display_value((row, f.column)).unwrap_or_default()))
+            }).collect()
+        };
+        self.sheet.set_hidden_rows(hidden);
+        // Move off of a hidden row, to the next shown row (or else the previous one)
+        if let Some((row, col)) = self.selected().filter(|s| self.sheet.is_hidden(s.0)) {
+            let shown = (row..self.bounds().0).chain((0..row).rev()).find(|&r| !self.sheet.is_hidden(r));
+            if let Some(shown) = shown {
+                self.sheet.set_selected_coords((shown, col));
+            }
+        }
+    }
+    /// Get the number of frozen (rows, columns)
+    pub fn frozen(&self) -> (usize, usize) {
+        self.frozen
+    }
+    /// Freeze a number of rows and columns
+    pub fn set_frozen(&mut self, frozen: (usize, usize)) {
+        self.frozen = frozen;
+        if !self.filters.is_empty() {
+            self.apply_filters();
+        }
+    }
+    /// Whether a row is hidden by filters
+    pub fn is_row_hidden(&self, row: usize) -> bool {
+        self.sheet.is_hidden(row)
+    }
+    /// Get the rows from first to last (inclusive) not hidden by filters
+    fn shown_rows(&self, first: usize, last: usize) -> Vec<usize> {
+        (first..=last).filter(|&r| !self.sheet.is_hidden(r)).collect()
+    }
+    /// Get the number of rows not hidden by filters
+    pub fn shown_row_count(&self) -> usize {
+        (0..self.bounds().0).filter(|&r| !self.sheet.is_hidden(r)).count()
+    }
+    /// Replace in the cells of a scope as a single undo step, returning the number of cells changed
+    pub fn replace(&mut self, scope: ReplaceScope, substitution: &Substitution, config: &ConfigData) -> usize {
+        let selected = self.selected().unwrap_or((0, 0));
+        let (rows, cols) = match scope {
+            ReplaceScope::Sheet => (0..self.bounds().0, 0..self.bounds().1),
+            ReplaceScope::CurrentRow => (selected.0..selected.0 + 1, 0..self.bounds().1),
+            ReplaceScope::CurrentColumn => (0..self.bounds().0, selected.1..selected.1 + 1),
+            ReplaceScope::Rows(start, end) => (start..cmp::min(end + 1, self.bounds().0), 0..self.bounds().1)
+        };
+        let mut cells: Vec<((usize, usize), String)> = Vec::new();
+        for row in rows {
+            for col in cols.clone() {
+                let Some(newval) = self.cell((row, col)).and_then(|v| substitution.apply(v)) else {
+                    continue;
+                };
+                cells.push(((row, col), newval));
+            }
+        }
+        let changed = cells.len();
+        if changed > 0 {
+            self.edit(Operation::SetCells(cells));
+            self.update_sheet_state(config);
+        }
+        changed
+    }
     /// Get the selected cell in the current sheet
     pub fn selected(&self) -> Option<(usize, usize)> {
         self.sheet.selected
@@ -145,9 +441,271 @@ impl SheetData {
     pub fn cell(&self, coords: (usize, usize)) -> Option<&str> {
         self.sheet.cell(coords)
     }
+    pub fn display_value(&self, coords: (usize, usize)) -> Option<String> {
+        self.sheet.display_value(coords)
+    }
     pub fn move_selected_coords(&mut self, delta: (isize, isize)) {
         self.sheet.move_selected_coords(delta);
     }
+    /// Start selecting a range from the selected cell
+    pub fn start_range(&mut self, mode: SelectionMode) {
+        self.sheet.start_range(mode);
+    }
+    /// Stop selecting a range
+    pub fn clear_range(&mut self) {
+        self.sheet.clear_range();
+    }
+    /// Whether a range (more than the selected cell) is being selected
+    pub fn has_range(&self) -> bool {
+        self.sheet.anchor.is_some()
+    }
+    /// Get the mode of the selected range
+    pub fn selection_mode(&self) -> SelectionMode {
+        self.sheet.selection_mode
+    }
+    /// Change the mode of the selected range, keeping its anchor
+    pub fn set_selection_mode(&mut self, mode: SelectionMode) {
+        self.sheet.selection_mode = mode;
+    }
+    /// Move the selected cell, extending the selected range (starting one if needed)
+    pub fn extend_range(&mut self, delta: (isize, isize)) {
+        if self.sheet.anchor.is_none() {
+            self.sheet.start_range(SelectionMode::Block);
+        }
+        self.sheet.move_selected_coords(delta);
+    }
+    /// Get the selected range as (top left, bottom right), inclusive
+    pub fn selection_bounds(&self) -> Option<((usize, usize), (usize, usize))> {
+        self.sheet.selection_bounds()
+    }
+    /// Whether a cell is in the selected range
+    pub fn in_selection(&self, coords: (usize, usize)) -> bool {
+        self.sheet.in_selection(coords)
+    }
+    /// Clear the values of the selected cells (as a single undo step)
+    pub fn clear_selected_cells(&mut self, config: &ConfigData) {
+        let Some((topleft, bottomright)) = self.selection_bounds() else {
+            return;
+        };
+        self.edit(Operation::SetCells(block_cells(topleft, bottomright, String::new())));
+        self.sheet.clear_range();
+        self.update_sheet_state(config);
+    }
+    /// Delete the rows of the selected range (as a single undo step), leaving rows hidden by filters alone
+    pub fn delete_selected_rows(&mut self, config: &ConfigData) -> bool {
+        let Some((topleft, bottomright)) = self.selection_bounds() else {
+            return false;
+        };
+        self.begin_transaction();
+        for row in self.shown_rows(topleft.0, bottomright.0).into_iter().rev() {
+            self.edit(Operation::DeleteRow(row));
+        }
+        self.sheet.clear_range();
+        self.sheet.set_selected_coords((cmp::min(topleft.0, self.bounds().0.saturating_sub(1)), self.selected().unwrap_or((0, 0)).1));
+        self.commit_transaction(config);
+        true
+    }
+    /// Delete the columns of the selected range (as a single undo step)
+    pub fn delete_selected_columns(&mut self, config: &ConfigData) -> bool {
+        let Some((topleft, bottomright)) = self.selection_bounds() else {
+            return false;
+        };
+        self.begin_transaction();
+        for col in (topleft.1..=bottomright.1).rev() {
+            self.edit(Operation::DeleteColumn(col));
+        }
+        self.sheet.clear_range();
+        self.sheet.set_selected_coords((self.selected().unwrap_or((0, 0)).0, cmp::min(topleft.1, self.bounds().1.saturating_sub(1))));
+        self.commit_transaction(config);
+        true
+    }
+    /// Fill the selected range (as a single undo step), returning whether anything changed
+    pub fn fill_selection(&mut self, fill: &Fill, config: &ConfigData) -> bool {
+        let Some((topleft, bottomright)) = self.selection_bounds() else {
+            return false;
+        };
+        let mut cells: Vec<((usize, usize), String)> = Vec::new();
+        for row in topleft.0..=bottomright.0 {
+            for col in topleft.1..=bottomright.1 {
+                // Get the source cell and the offset from it
+                let newval = match fill {
+                    Fill::Value(v) => v.clone(),
+                    Fill::Down | Fill::Right => {
+                        let source = if *fill == Fill::Down { (topleft.0, col) } else { (row, topleft.1) };
+                        if source == (row, col) {
+                            continue;
+                        }
+                        let text = self.cell(source).unwrap_or("");
+                        let offset = ((row - source.0) as isize, (col - source.1) as isize);
+                        formula::translate_references(text, offset).unwrap_or(text.to_string())
+                    }
+                };
+                if self.cell((row, col)) != Some(newval.as_str()) {
+                    cells.push(((row, col), newval));
+                }
+            }
+        }
+        self.sheet.clear_range();
+        let changed = !cells.is_empty();
+        if changed {
+            self.edit(Operation::SetCells(cells));
+            self.update_sheet_state(config);
+        }
+        changed
+    }
+    /// Get the cells a register operation of a kind applies to: the selected range, extended to whole rows or columns
+    fn register_bounds(&self, kind: RegisterKind) -> Option<((usize, usize), (usize, usize))> {
+        let (topleft, bottomright) = self.selection_bounds()?;
+        let (rowmax, colmax) = (self.bounds().0 - 1, self.bounds().1 - 1);
+        Some(match kind {
+            RegisterKind::Cells => (topleft, bottomright),
+            RegisterKind::Rows => ((topleft.0, 0), (bottomright.0, colmax)),
+            RegisterKind::Columns => ((0, topleft.1), (rowmax, bottomright.1))
+        })
+    }
+    /// Copy the selected cells (or their whole rows or columns) into a register (the unnamed register if None)
+    /// Whole rows hidden by filters are left out
+    pub fn yank_selection(&mut self, name: Option<char>, kind: RegisterKind) -> bool {
+        let Some((topleft, bottomright)) = self.register_bounds(kind) else {
+            return false;
+        };
+        let rows = match kind {
+            RegisterKind::Rows => self.shown_rows(topleft.0, bottomright.0),
+            RegisterKind::Cells | RegisterKind::Columns => (topleft.0..=bottomright.0).collect()
+        };
+        let cells: Vec<Vec<String>> = rows.iter().map(|&row| {
+            (topleft.1..=bottomright.1).map(|col| self.cell((row, col)).unwrap_or("").to_string()).collect()
+        }).collect();
+        self.registers.set(name, Register { cells, kind, origin: topleft });
+        // Other programs get the displayed values (formula results), as tab-separated text
+        let values: Vec<Vec<String>> = rows.iter().map(|&row| {
+            (topleft.1..=bottomright.1).map(|col| self.display_value((row, col)).unwrap_or_default()).collect()
+        }).collect();
+        self.clipboard_text = Some(csvformat::generate(&values, '\t').trim_end_matches('\n').to_string());
+        // Go back to the start of the selected range, like vim
+        let selected = self.selected().unwrap_or(topleft);
+        let anchor = self.sheet.anchor.unwrap_or(selected);
+        self.sheet.clear_range();
+        self.sheet.set_selected_coords((cmp::min(selected.0, anchor.0), cmp::min(selected.1, anchor.1)));
+        true
+    }
+    /// Take the text of the last yank or cut, to copy to the system clipboard (None if already taken)
+    pub fn take_clipboard_text(&mut self) -> Option<String> {
+        self.clipboard_text.take()
+    }
+    /// Move the selected cells (or their whole rows or columns) into a register (as a single undo step)
+    /// Cut cells are cleared, and cut rows or columns are deleted (except whole rows hidden by filters)
+    pub fn cut_selection(&mut self, name: Option<char>, kind: RegisterKind, config: &ConfigData) -> bool {
+        let Some((topleft, bottomright)) = self.register_bounds(kind) else {
+            return false;
+        };
+        self.yank_selection(name, kind);
+        self.begin_transaction();
+        match kind {
+            RegisterKind::Cells => {
+                self.edit(Operation::SetCells(block_cells(topleft, bottomright, String::new())));
+            }
+            RegisterKind::Rows => {
+                for row in self.shown_rows(topleft.0, bottomright.0).into_iter().rev() {
+                    self.edit(Operation::DeleteRow(row));
+                }
+            }
+            RegisterKind::Columns => {
+                for col in (topleft.1..=bottomright.1).rev() {
+                    self.edit(Operation::DeleteColumn(col));
+                }
+            }
+        }
+        let selected = self.selected().unwrap_or((0, 0));
+        self.sheet.set_selected_coords((cmp::min(selected.0, self.bounds().0 - 1), cmp::min(selected.1, self.bounds().1 - 1)));
+        self.commit_transaction(config);
+        true
+    }
+    /// Paste a register (the unnamed register if None) count times at the selected cell (as a single undo step)
+    /// Cells are pasted over the cells from the selected one (copies side by side), and rows or columns are inserted after it (or before)
+    /// The sheet grows if needed, and relative formula references move with the pasted cells
+    pub fn paste(&mut self, name: Option<char>, before: bool, count: usize, config: &ConfigData) -> bool {
+        let (Some(register), Some(selected)) = (self.registers.get(name).cloned(), self.selected()) else {
+            return false;
+        };
+        let (height, width) = register.size();
+        if height == 0 || width == 0 || count == 0 {
+            return false;
+        }
+        // Make room for the register
+        self.begin_transaction();
+        let dest = match register.kind {
+            RegisterKind::Cells => selected,
+            RegisterKind::Rows => {
+                let row = if before { selected.0 } else { selected.0 + 1 };
+                for _ in 0..height * count {
+                    self.edit(Operation::InsertRow(row, Vec::new()));
+                }
+                (row, 0)
+            }
+            RegisterKind::Columns => {
+                let col = if before { selected.1 } else { selected.1 + 1 };
+                for _ in 0..width * count {
+                    self.edit(Operation::InsertColumn(col, Vec::new(), None));
+                }
+                (0, col)
+            }
+        };
+        for i in 0..count {
+            let copydest = match register.kind {
+                RegisterKind::Rows => (dest.0 + i * height, dest.1),
+                RegisterKind::Cells | RegisterKind::Columns => (dest.0, dest.1 + i * width)
+            };
+            let offset = (copydest.0 as isize - register.origin.0 as isize, copydest.1 as isize - register.origin.1 as isize);
+            self.set_block(copydest, &register.cells, offset);
+        }
+        self.sheet.clear_range();
+        self.sheet.set_selected_coords(match register.kind {
+            RegisterKind::Cells => dest,
+            RegisterKind::Rows => (dest.0, selected.1),
+            RegisterKind::Columns => (selected.0, dest.1)
+        });
+        self.commit_transaction(config);
+        true
+    }
+    /// Paste delimited text (ex. rows copied from another spreadsheet) over the cells from the selected cell
+    /// (as a single undo step), returning the size of the pasted block or a description of the error
+    /// The text is tab-separated if it has tabs, and otherwise uses the sniffed delimiter
+    pub fn paste_text(&mut self, text: &str, config: &ConfigData) -> Result<(usize, usize), String> {
+        let Some(selected) = self.selected() else {
+            return Err("No cell selected.".to_string());
+        };
+        let delimiter = if text.contains('\t') { '\t' } else { csvformat::sniff_delimiter(text) };
+        let cells = csvformat::parse(text, delimiter).map_err(|e| format!("Could not paste: {}", e))?;
+        let size = (cells.len(), cells.iter().map(|row| row.len()).max().unwrap_or(0));
+        if size.0 == 0 || size.1 == 0 {
+            return Err("Nothing to paste.".to_string());
+        }
+        self.begin_transaction();
+        self.set_block(selected, &cells, (0, 0));
+        self.sheet.clear_range();
+        self.commit_transaction(config);
+        Ok(size)
+    }
+    /// Set a block of cells from a top left cell, growing the sheet if needed
+    /// Relative formula references are moved by an offset (the distance from where the cells came from)
+    fn set_block(&mut self, dest: (usize, usize), cells: &[Vec<String>], offset: (isize, isize)) {
+        let width = cells.iter().map(|row| row.len()).max().unwrap_or(0);
+        let size = (cmp::max(dest.0 + cells.len(), self.bounds().0), cmp::max(dest.1 + width, self.bounds().1));
+        self.edit(Operation::Resize(size));
+        let mut newcells: Vec<((usize, usize), String)> = Vec::new();
+        for (i, line) in cells.iter().enumerate() {
+            for (j, text) in line.iter().enumerate() {
+                let newval = if offset == (0, 0) {
+                    text.clone()
+                } else {
+                    formula::translate_references(text, offset).unwrap_or(text.to_string())
+                };
+                newcells.push(((dest.0 + i, dest.1 + j), newval));
+            }
+        }
+        self.edit(Operation::SetCells(newcells));
+    }
     pub fn set_selected_coords(&mut self, coords: (usize, usize)) {
         self.sheet.set_selected_coords(coords);
     }
@@ -155,46 +713,169 @@ impl SheetData {
         self.sheet.selected_cell_value()
     }
     pub fn set_cell_value(&mut self, coords: (usize, usize), newval: String, config: &ConfigData) {
-        self.sheet.set_cell_value(coords, newval);
+        self.edit(Operation::SetCells(vec![(coords, newval)]));
         self.update_sheet_state(config);
     }
     pub fn set_selected_cell_value(&mut self, newval: String, config: &ConfigData) {
-        self.sheet.set_selected_cell_value(newval);
-        self.update_sheet_state(config);
+        let Some(selected) = self.selected() else {
+            return;
+        };
+        self.set_cell_value(selected, newval, config);
     }
     pub fn delete_row(&mut self, rowcoord: usize, config: &ConfigData) -> bool {
-        self.sheet.delete_row(rowcoord);
+        self.edit(Operation::DeleteRow(rowcoord));
         self.update_sheet_state(config);
         true
     }
     pub fn delete_column(&mut self, colcoord: usize, config: &ConfigData) -> bool {
-        self.sheet.delete_column(colcoord);
+        self.edit(Operation::DeleteColumn(colcoord));
         self.update_sheet_state(config);
         true
     }
     pub fn insert_row(&mut self, rowcoord: usize, config: &ConfigData) -> bool {
-        self.sheet.insert_row(rowcoord);
+        self.edit(Operation::InsertRow(rowcoord, Vec::new()));
         self.update_sheet_state(config);
         true
     }
     pub fn insert_column(&mut self, colcoord: usize, config: &ConfigData) -> bool {
-        self.sheet.insert_column(colcoord);
+        self.edit(Operation::InsertColumn(colcoord, Vec::new(), None));
         self.update_sheet_state(config);
         true
     }
-    pub fn sort_column(&mut self, colcoord: usize, config: &ConfigData) -> bool {
-        self.sheet.sort_column(colcoord);
+    pub fn sort_rows(&mut self, rowstart: usize, rowend: usize, keys: &[SortKey], config: &ConfigData) -> bool {
+        let Some(order) = self.sheet.sorted_row_order(rowstart, rowend, keys) else {
+            return false;
+        };
+        self.edit(Operation::PermuteRows(rowstart, order));
         self.update_sheet_state(config);
         true
     }
-    pub fn sort_column_bounded(&mut self, colcoord: usize, rowstart: usize, rowend: usize, config: &ConfigData) -> bool {
-        self.sheet.sort_column_bounded(colcoord, rowstart, rowend);
+    pub fn sort_columns(&mut self, colstart: usize, colend: usize, keys: &[SortKey], config: &ConfigData) -> bool {
+        let Some(order) = self.sheet.sorted_column_order(colstart, colend, keys) else {
+            return false;
+        };
+        self.edit(Operation::PermuteColumns(colstart, order));
         self.update_sheet_state(config);
         true
     }
-    pub fn sort_column_bounded_num(&mut self, colcoord: usize, rowstart: usize, rowend: usize, config: &ConfigData) -> bool {
-        self.sheet.sort_column_bounded_num(colcoord, rowstart, rowend);
-        self.update_sheet_state(config);
-        true
+}
+
+/// Get the max number of undo steps to keep
+fn history_limit(config: &ConfigData) -> usize {
+    config.get_value("historysize").unwrap_or(configdata::DEFAULT_HISTORY_SIZE).try_into().unwrap_or(0)
+}
+
+/// Get whether the undo history is saved with files (the `undofile` config value)
+fn undo_file_enabled(config: &ConfigData) -> bool {
+    config.get_value("undofile").unwrap_or(0) != 0
+}
+
+/// Get every cell of a block (top left to bottom right, inclusive) set to a value
+fn block_cells(topleft: (usize, usize), bottomright: (usize, usize), value: String) -> Vec<((usize, usize), String)> {
+    (topleft.0..=bottomright.0)
+        .flat_map(|row| (topleft.1..=bottomright.1).map(move |col| (row, col)))
+        .map(|coords| (coords, value.clone()))
+        .collect()
+}
+
+/// Get the path of a file stored next to another file, hidden (ex. `dir/.data.csv.{suffix}` for `dir/data.csv`)
+fn sidecar_path(path: &str, suffix: &str) -> PathBuf {
+    let path = Path::new(path);
+    let filename = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    path.with_file_name(format!(".{}.{}", filename, suffix))
+}
+
+impl Default for SheetData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write a file in a fresh temporary directory, returning its path
+    fn temp_file(dir: &str, name: &str, contents: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("sheatfish_{}_{}", dir, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, contents).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn failed_load_keeps_the_open_sheet() {
+        let config = ConfigData::with_defaults();
+        let path = temp_file("failedload", "good.csv", "a,b\n1,2\n");
+        let mut data = SheetData::new();
+        data.load_file(&path, &config).unwrap();
+        data.set_cell_value((1, 1), "3".to_string(), &config);
+        let bad = temp_file("failedload_bad", "bad.csv", "a,\"b\n");
+        assert!(data.load_file(&bad, &config).is_err());
+        assert!(data.load_file(&format!("{}.missing", path), &config).is_err());
+        assert_eq!(data.file_path, path);
+        assert_eq!(data.cell((1, 1)), Some("3"));
+        assert!(data.unsaved);
+    }
+
+    #[test]
+    fn delimiter_change_stays_unsaved() {
+        let config = ConfigData::with_defaults();
+        let path = temp_file("delimiter", "data.csv", "a,b\n");
+        let mut data = SheetData::new();
+        data.load_file(&path, &config).unwrap();
+        data.set_delimiter(';');
+        assert!(data.unsaved);
+        data.set_cell_value((0, 0), "x".to_string(), &config);
+        data.undo();
+        assert!(data.unsaved);
+        data.set_delimiter(',');
+        assert!(!data.unsaved);
+        data.set_delimiter(';');
+        assert!(data.save_file(&path, &config));
+        assert!(!data.unsaved);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a;b\n");
+    }
+
+    #[test]
+    fn row_deletes_skip_filtered_rows() {
+        let config = ConfigData::with_defaults();
+        let mut data = SheetData::new();
+        data.paste_text("a\tkeep\nb\thide\nc\tkeep\nd\thide\ne\tkeep", &config).unwrap();
+        data.add_filter(Filter::parse(&["1", "=", "keep"]).unwrap()).unwrap();
+        data.set_selected_coords((0, 0));
+        data.start_range(SelectionMode::Rows);
+        data.set_selected_coords((2, 0));
+        assert!(data.delete_selected_rows(&config));
+        data.clear_filters();
+        assert_eq!((0..3).map(|r| data.cell((r, 0)).unwrap_or("")).collect::<Vec<_>>(), ["b", "d", "e"]);
+        data.add_filter(Filter::parse(&["1", "=", "keep"]).unwrap()).unwrap();
+        data.set_selected_coords((2, 0));
+        data.start_range(SelectionMode::Rows);
+        data.set_selected_coords((0, 0));
+        assert!(data.cut_selection(None, RegisterKind::Rows, &config));
+        data.clear_filters();
+        assert_eq!((0..2).map(|r| data.cell((r, 0)).unwrap_or("")).collect::<Vec<_>>(), ["b", "d"]);
+        let cut = &data.registers.get(None).unwrap().cells;
+        assert_eq!((cut.len(), &cut[0][..2]), (1, &["e".to_string(), "keep".to_string()][..]));
+    }
+
+    #[test]
+    fn column_widths_are_saved_with_the_file() {
+        let config = ConfigData::with_defaults();
+        let path = temp_file("widths", "data.csv", "a,b\n");
+        let mut data = SheetData::new();
+        data.load_file(&path, &config).unwrap();
+        data.set_column_width(1, Some(12));
+        data.autofit_columns(&[0], 30);
+        assert!(data.unsaved);
+        assert!(!data.column_widths_path().exists());
+        assert!(data.save_file(&path, &config));
+        assert_eq!(fs::read_to_string(data.column_widths_path()).unwrap(), "0, 1\n1, 12\n");
+        data.load_file(&path, &config).unwrap();
+        assert_eq!(data.column_width(1, &config), 12);
+        assert!(!data.unsaved);
     }
 }
@@ -0,0 +1,261 @@
+use std::cmp::Ordering;
+
+/// How the values of a sort key are compared
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Comparator {
+    Text, // By string
+    CaseInsensitive, // By string, ignoring case
+    Numeric, // By number (non-numbers after numbers, in their original order)
+    Natural, // By string, with runs of digits compared as numbers ("file2" before "file10")
+    Date // By date (YYYY-MM-DD, YYYY/MM/DD, or MM/DD/YYYY, with an optional HH:MM[:SS] time; non-dates after dates)
+}
+
+/// A column (or a row, when sorting horizontally) to sort by
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SortKey {
+    pub index: usize,
+    pub descending: bool,
+    pub comparator: Comparator
+}
+
+impl SortKey {
+    /// Create an ascending text key
+    pub fn new(index: usize) -> SortKey {
+        SortKey { index, descending: false, comparator: Comparator::Text }
+    }
+    /// Parse a key like `2`, `2:desc`, or `2:num:desc`
+    pub fn parse(text: &str) -> Option<SortKey> {
+        let mut parts = text.split(':');
+        let mut res = SortKey::new(parts.next()?.parse().ok()?);
+        for modifier in parts {
+            match modifier {
+                "asc" | "a" => res.descending = false,
+                "desc" | "d" => res.descending = true,
+                "str" | "s" => res.comparator = Comparator::Text,
+                "nocase" | "i" => res.comparator = Comparator::CaseInsensitive,
+                "num" | "n" => res.comparator = Comparator::Numeric,
+                "nat" => res.comparator = Comparator::Natural,
+                "date" => res.comparator = Comparator::Date,
+                _ => return None
+            }
+        }
+        Some(res)
+    }
+    /// Compare two values by this key (empty values always go last)
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        match (a.trim().is_empty(), b.trim().is_empty()) {
+            (true, true) => return Ordering::Equal,
+            (true, false) => return Ordering::Greater,
+            (false, true) => return Ordering::Less,
+            _ => {}
+        }
+        let ord = match self.comparator {
+            Comparator::Text => a.cmp(b),
+            Comparator::CaseInsensitive => a.to_lowercase().cmp(&b.to_lowercase()),
+            Comparator::Natural => compare_natural(a, b),
+            Comparator::Numeric => {
+                let parse = |v: &str| v.trim().parse::<f64>().ok().filter(|n| n.is_finite());
+                match compare_parsed(parse(a), parse(b), |x, y| x.total_cmp(y)) {
+                    Ok(ord) => ord,
+                    Err(ord) => return ord
+                }
+            }
+            Comparator::Date => match compare_parsed(parse_date(a), parse_date(b), |x, y| x.cmp(y)) {
+                Ok(ord) => ord,
+                Err(ord) => return ord
+            }
+        };
+        if self.descending {
+            ord.reverse()
+        } else {
+            ord
+        }
+    }
+}
+
+/// Compare values that may have parsed into a comparable form
+/// Values that did not parse always go after those that did (returned as Err, so the direction does not apply)
+fn compare_parsed<T>(a: Option<T>, b: Option<T>, cmp: fn(&T, &T) -> Ordering) -> Result<Ordering, Ordering> {
+    match (a, b) {
+        (Some(x), Some(y)) => Ok(cmp(&x, &y)),
+        (Some(_), None) => Err(Ordering::Less),
+        (None, Some(_)) => Err(Ordering::Greater),
+        (None, None) => Err(Ordering::Equal)
+    }
+}
+
+/// Compare strings naturally: runs of digits by value, everything else case-insensitively
+fn compare_natural(a: &str, b: &str) -> Ordering {
+    let mut achars = a.chars().peekable();
+    let mut bchars = b.chars().peekable();
+    loop {
+        match (achars.peek().copied(), bchars.peek().copied()) {
+            (None, None) => return a.cmp(b), // Equal ignoring case and leading zeros, so fall back to exact order
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                // Compare the runs of digits by value: without leading zeros, longer is bigger
+                let mut arun = String::new();
+                while let Some(c) = achars.next_if(|c| c.is_ascii_digit()) {
+                    arun.push(c);
+                }
+                let mut brun = String::new();
+                while let Some(c) = bchars.next_if(|c| c.is_ascii_digit()) {
+                    brun.push(c);
+                }
+                let arun = arun.trim_start_matches('0');
+                let brun = brun.trim_start_matches('0');
+                let ord = arun.len().cmp(&brun.len()).then_with(|| arun.cmp(brun));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ord = x.to_lowercase().cmp(y.to_lowercase());
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                achars.next();
+                bchars.next();
+            }
+        }
+    }
+}
+
+/// Parse a date (and optional time) into a comparable (year, month, day, hour, minute, second)
+fn parse_date(text: &str) -> Option<(i32, u32, u32, u32, u32, u32)> {
+    let text = text.trim();
+    let (datepart, timepart) = match text.find(['T', ' ']) {
+        Some(i) => (&text[..i], Some(text[i + 1..].trim())),
+        None => (text, None)
+    };
+    let sep = if datepart.contains('-') { '-' } else { '/' };
+    let fields: Vec<&str> = datepart.split(sep).collect();
+    if fields.len() != 3 {
+        return None;
+    }
+    let (year, month, day) = if fields[0].len() == 4 {
+        (fields[0].parse().ok()?, fields[1].parse().ok()?, fields[2].parse().ok()?)
+    } else {
+        (fields[2].parse().ok()?, fields[0].parse().ok()?, fields[1].parse().ok()?)
+    };
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    let mut time = [0u32; 3];
+    if let Some(timepart) = timepart.filter(|t| !t.is_empty()) {
+        let timepart = timepart.trim_end_matches('Z');
+        for (i, field) in timepart.split(':').enumerate() {
+            if i >= 3 {
+                return None;
+            }
+            time[i] = field.split('.').next()?.parse().ok()?;
+        }
+    }
+    Some((year, month, day, time[0], time[1], time[2]))
+}
+
+/// Options of a sort command
+#[derive(Clone, Debug, PartialEq)]
+pub struct SortOptions {
+    pub horizontal: bool, // Sort columns by the values in rows, instead of rows by the values in columns
+    pub range: Option<(usize, usize)>, // Rows (or columns) to sort, inclusive
+    pub keys: Vec<SortKey>, // Empty means the selected column (or row)
+    pub header: bool // Keep the first row (or column) in place
+}
+
+impl SortOptions {
+    /// Parse the terms after `sort`: `[row] [{start} {end}] [header] [by {key} ...]`
+    pub fn parse(terms: &[&str]) -> Result<SortOptions, String> {
+        let mut res = SortOptions { horizontal: false, range: None, keys: Vec::new(), header: false };
+        let mut i = 0;
+        if terms.first() == Some(&"row") || terms.first() == Some(&"r") {
+            res.horizontal = true;
+            i += 1;
+        }
+        let mut in_keys = false;
+        while i < terms.len() {
+            let term = terms[i];
+            if term == "by" {
+                in_keys = true;
+            } else if term == "header" || term == "h" {
+                res.header = true;
+            } else if in_keys {
+                res.keys.push(SortKey::parse(term).ok_or(format!("Invalid sort key \"{}\".", term))?);
+            } else if res.range.is_none() && i + 1 < terms.len() {
+                let start = term.parse::<usize>().map_err(|_| format!("Invalid sort bound \"{}\".", term))?;
+                let end = terms[i + 1].parse::<usize>().map_err(|_| format!("Invalid sort bound \"{}\".", terms[i + 1]))?;
+                res.range = Some((start, end));
+                i += 1;
+            } else {
+                return Err(format!("Unexpected sort option \"{}\".", term));
+            }
+            i += 1;
+        }
+        if in_keys && res.keys.is_empty() {
+            return Err("Expected a sort key after \"by\".".to_string());
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sort values by a key, as the sort command would
+    fn sorted(key: &str, values: &[&'static str]) -> Vec<&'static str> {
+        let key = SortKey::parse(key).unwrap();
+        let mut res = values.to_vec();
+        res.sort_by(|a, b| key.compare(a, b));
+        res
+    }
+
+    #[test]
+    fn compares_naturally() {
+        assert_eq!(compare_natural("file2", "file10"), Ordering::Less);
+        assert_eq!(compare_natural("File10", "file9"), Ordering::Greater);
+        assert_eq!(compare_natural("a007", "a7"), Ordering::Less);
+        assert_eq!(compare_natural("a7", "a7b"), Ordering::Less);
+        assert_eq!(compare_natural("x", "x"), Ordering::Equal);
+        assert_eq!(sorted("0:nat", &["v1.10", "v1.9", "V1.2", "v1"]), ["v1", "V1.2", "v1.9", "v1.10"]);
+    }
+
+    #[test]
+    fn parses_dates() {
+        assert_eq!(parse_date("2024-03-05"), Some((2024, 3, 5, 0, 0, 0)));
+        assert_eq!(parse_date("03/05/2024 14:30"), Some((2024, 3, 5, 14, 30, 0)));
+        assert_eq!(parse_date("2024/03/05T14:30:15.5Z"), Some((2024, 3, 5, 14, 30, 15)));
+        assert_eq!(parse_date("2024-13-01"), None);
+        assert_eq!(parse_date("2024-03"), None);
+        assert_eq!(parse_date("2024-03-05 1:2:3:4"), None);
+        assert_eq!(sorted("0:date:desc", &["soon", "01/02/2024", "2023-12-31", ""]), ["01/02/2024", "2023-12-31", "soon", ""]);
+    }
+
+    #[test]
+    fn puts_non_numbers_after_numbers() {
+        assert_eq!(sorted("0:num", &["10", "b", "", "-2.5", "a", "inf", "3"]), ["-2.5", "3", "10", "b", "a", "inf", ""]);
+        assert_eq!(sorted("0:num:desc", &["10", "b", "", "-2.5", "a", "3"]), ["10", "3", "-2.5", "b", "a", ""]);
+        assert_eq!(sorted("0", &["10", "9", "B", "a"]), ["10", "9", "B", "a"]);
+        assert_eq!(sorted("0:i", &["b", "A", "c"]), ["A", "b", "c"]);
+    }
+
+    #[test]
+    fn parses_options() {
+        assert_eq!(SortKey::parse("2:n:d"), Some(SortKey { index: 2, descending: true, comparator: Comparator::Numeric }));
+        assert_eq!(SortKey::parse("2:bad"), None);
+        assert_eq!(SortKey::parse("x"), None);
+        let options = SortOptions::parse(&["row", "1", "5", "header", "by", "3:desc", "0"]).unwrap();
+        assert_eq!(options, SortOptions {
+            horizontal: true,
+            range: Some((1, 5)),
+            keys: vec![SortKey { descending: true, ..SortKey::new(3) }, SortKey::new(0)],
+            header: true
+        });
+        assert_eq!(SortOptions::parse(&[]).unwrap(), SortOptions { horizontal: false, range: None, keys: Vec::new(), header: false });
+        assert!(SortOptions::parse(&["by"]).is_err());
+        assert!(SortOptions::parse(&["1"]).is_err());
+        assert!(SortOptions::parse(&["1", "x"]).is_err());
+        assert!(SortOptions::parse(&["by", "1:up"]).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Shown at the end (or start) of text that was cut off
+const ELLIPSIS: &str = "…";
+
+/// Get the text as it is shown on one line (line breaks as ↵, other control characters as spaces)
+fn one_line(text: &str) -> String {
+    text.replace("\r\n", "\n")
+        .chars()
+        .map(|c| if c == '\n' { '↵' } else if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+/// Get the number of terminal columns text takes on one line
+pub fn display_width(text: &str) -> usize {
+    one_line(text).graphemes(true).map(|g| g.width()).sum()
+}
+
+/// Fit text into exactly a number of terminal columns: cut off at the end (with an ellipsis) if too wide, padded with spaces if too narrow
+pub fn fit(text: &str, width: usize) -> String {
+    let text = one_line(text);
+    let fullwidth: usize = text.graphemes(true).map(|g| g.width()).sum();
+    let mut res = String::new();
+    let mut used = 0;
+    if fullwidth <= width {
+        res = text;
+        used = fullwidth;
+    } else if width > 0 {
+        // Keep room for the ellipsis, never splitting a grapheme cluster (so a wide character may leave a gap)
+        for grapheme in text.graphemes(true) {
+            let w = grapheme.width();
+            if used + w + ELLIPSIS.width() > width {
+                break;
+            }
+            res.push_str(grapheme);
+            used += w;
+        }
+        res.push_str(ELLIPSIS);
+        used += ELLIPSIS.width();
+    }
+    res.push_str(&" ".repeat(width.saturating_sub(used)));
+    res
+}
+
+/// Fit text into at most a number of terminal columns, keeping the end (cut off at the start, with an ellipsis, if too wide)
+pub fn fit_end(text: &str, width: usize) -> String {
+    let text = one_line(text);
+    if text.graphemes(true).map(|g| g.width()).sum::<usize>() <= width {
+        return text;
+    } else if width == 0 {
+        return String::new();
+    }
+    let mut kept: Vec<&str> = Vec::new();
+    let mut used = ELLIPSIS.width();
+    for grapheme in text.graphemes(true).rev() {
+        let w = grapheme.width();
+        if used + w > width {
+            break;
+        }
+        kept.push(grapheme);
+        used += w;
+    }
+    kept.reverse();
+    format!("{}{}", ELLIPSIS, kept.concat())
+}